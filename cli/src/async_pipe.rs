@@ -5,6 +5,7 @@
 
 use crate::{constants::APPLICATION_NAME, util::errors::CodeError};
 use async_trait::async_trait;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -38,6 +39,16 @@ cfg_if::cfg_if! {
 			pub async fn accept(&mut self) -> Result<AsyncPipe, CodeError> {
 				self.0.accept().await.map_err(CodeError::AsyncPipeListenerFailed).map(|(s, _)| s)
 			}
+
+			/// Wraps a listener that was created elsewhere, such as one passed
+			/// with socket activation.
+			pub fn from_std(listener: std::os::unix::net::UnixListener) -> Result<Self, CodeError> {
+				listener
+					.set_nonblocking(true)
+					.and_then(|_| tokio::net::UnixListener::from_std(listener))
+					.map(AsyncPipeListener)
+					.map_err(CodeError::AsyncPipeListenerFailed)
+			}
 		}
 
 		pub fn socket_stream_split(pipe: AsyncPipe) -> (AsyncPipeReadHalf, AsyncPipeWriteHalf) {
@@ -238,9 +249,12 @@ pub fn get_socket_name() -> PathBuf {
 	}
 }
 
+/// Read and write halves of an accepted connection, and the address of the
+/// client when it's connected over TCP.
 pub type AcceptedRW = (
 	Box<dyn AsyncRead + Send + Unpin>,
 	Box<dyn AsyncWrite + Send + Unpin>,
+	Option<IpAddr>,
 );
 
 #[async_trait]
@@ -253,18 +267,18 @@ impl AsyncRWAccepter for AsyncPipeListener {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError> {
 		let pipe = self.accept().await?;
 		let (read, write) = socket_stream_split(pipe);
-		Ok((Box::new(read), Box::new(write)))
+		Ok((Box::new(read), Box::new(write), None))
 	}
 }
 
 #[async_trait]
 impl AsyncRWAccepter for TcpListener {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError> {
-		let (stream, _) = self
+		let (stream, addr) = self
 			.accept()
 			.await
 			.map_err(CodeError::AsyncPipeListenerFailed)?;
 		let (read, write) = tokio::io::split(stream);
-		Ok((Box::new(read), Box::new(write)))
+		Ok((Box::new(read), Box::new(write), Some(addr.ip())))
 	}
 }
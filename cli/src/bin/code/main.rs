@@ -16,14 +16,21 @@ use cli::{
 		errors::{wrap, AnyError},
 		is_integrated_cli,
 		prereqs::PreReqChecker,
+		socket_activation,
 	},
 };
 use legacy_args::try_parse_legacy;
 use opentelemetry::sdk::trace::TracerProvider as SdkTracerProvider;
 use opentelemetry::trace::TracerProvider;
 
+fn main() -> Result<(), std::convert::Infallible> {
+	// changes the environment, so it must run before the runtime starts threads
+	socket_activation::take_env();
+	run()
+}
+
 #[tokio::main]
-async fn main() -> Result<(), std::convert::Infallible> {
+async fn run() -> Result<(), std::convert::Infallible> {
 	let raw_args = std::env::args_os().collect::<Vec<_>>();
 	let parsed = try_parse_legacy(&raw_args)
 		.map(|core| args::AnyCli::Integrated(args::IntegratedCli { core }))
@@ -99,9 +106,10 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				tunnels::command_shell(context!(), cs_args).await
 			}
 
-			Some(args::Commands::ServeWeb(sw_args)) => {
-				serve_web::serve_web(context!(), sw_args).await
-			}
+			Some(args::Commands::ServeWeb(mut sw_args)) => match sw_args.subcommand.take() {
+				Some(command) => serve_web::subcommand(context!(), *sw_args, command).await,
+				None => serve_web::serve_web(context!(), *sw_args).await,
+			},
 
 			Some(args::Commands::Tunnel(tunnel_args)) => match tunnel_args.subcommand {
 				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context!()).await,
@@ -176,7 +176,7 @@ pub enum Commands {
 
 	/// Runs a local web version of VS Code.
 	#[clap(about = concatcp!("Runs a local web version of ", constants::PRODUCT_NAME_LONG))]
-	ServeWeb(ServeWebArgs),
+	ServeWeb(Box<ServeWebArgs>),
 
 	/// Runs the control server on process stdin/stdout
 	#[clap(hide = true)]
@@ -185,6 +185,9 @@ pub enum Commands {
 
 #[derive(Args, Debug, Clone)]
 pub struct ServeWebArgs {
+	#[clap(subcommand)]
+	pub subcommand: Option<ServeWebSubcommand>,
+
 	/// Host to listen on, defaults to 'localhost'
 	#[clap(long)]
 	pub host: Option<String>,
@@ -194,6 +197,15 @@ pub struct ServeWebArgs {
 	/// Port to listen on. If 0 is passed a random free port is picked.
 	#[clap(long, default_value_t = 8000)]
 	pub port: u16,
+	/// Address to listen on, as `tcp://<ip>:<port>` or `unix://<path>`. Can be
+	/// given multiple times, such as to listen on both IPv4 and IPv6. TLS
+	/// options only apply to TCP addresses.
+	#[clap(long, conflicts_with_all = ["host", "socket_path", "port"])]
+	pub listen: Vec<String>,
+	/// Path under which the web UI is served, such as `/code` when a reverse
+	/// proxy forwards `https://example.com/code/` to this server.
+	#[clap(long)]
+	pub server_base_path: Option<String>,
 	/// A secret that must be included with all requests.
 	#[clap(long)]
 	pub connection_token: Option<String>,
@@ -215,6 +227,155 @@ pub struct ServeWebArgs {
 	/// Set the root path for extensions.
 	#[clap(long)]
 	pub extensions_dir: Option<String>,
+	/// Additional argument to pass to every server version, such as
+	/// `--server-arg=--log=trace`. Can be given multiple times.
+	#[clap(long, allow_hyphen_values = true)]
+	pub server_arg: Vec<String>,
+	/// Environment variable to set for every server version, as `KEY=VALUE`.
+	/// Can be given multiple times.
+	#[clap(long, value_parser = parse_env_var)]
+	pub server_env: Vec<(String, String)>,
+	/// Path to a PEM-encoded certificate (chain) to serve HTTPS with. The file is reloaded when it changes.
+	#[clap(long, requires = "tls_key", conflicts_with_all = ["socket_path", "tls_self_signed"])]
+	pub tls_cert: Option<String>,
+	/// Path to the PEM-encoded PKCS#8 private key for `--tls-cert`.
+	#[clap(long, requires = "tls_cert")]
+	pub tls_key: Option<String>,
+	/// Serve HTTPS using a generated self-signed certificate. Only intended for local testing.
+	#[clap(long, conflicts_with = "socket_path")]
+	pub tls_self_signed: bool,
+	/// Name that admin subcommands check the server's certificate against. Defaults
+	/// to the listen address, or 'localhost' when listening on localhost or all addresses.
+	#[clap(long)]
+	pub tls_server_name: Option<String>,
+	/// Quality of the server to serve. Defaults to the quality of the CLI.
+	#[clap(long, value_enum)]
+	pub quality: Option<options::Quality>,
+	/// Always serve this commit of the server instead of the latest release.
+	#[clap(long, conflicts_with = "version")]
+	pub commit: Option<String>,
+	/// Always serve this version (x.y.z) of the server instead of the latest release.
+	#[clap(long)]
+	pub version: Option<String>,
+	/// Serve the server from a local archive (.tar.gz or .zip) without
+	/// contacting the update service, for use on offline machines.
+	#[clap(long, conflicts_with_all = ["quality", "commit", "version"])]
+	pub server_archive: Option<String>,
+	/// Number of seconds after which a server version without connections is stopped.
+	#[clap(long, default_value_t = 60 * 60)]
+	pub idle_timeout: u64,
+	/// Number of seconds to wait for open connections, such as websockets, to
+	/// close when shutting down before the servers are stopped.
+	#[clap(long, default_value_t = 10)]
+	pub shutdown_grace_period: u64,
+	/// Exit after this many seconds without any connections. Useful with
+	/// systemd socket activation, which starts serve-web again on demand.
+	#[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+	pub idle_exit_timeout: Option<u64>,
+	/// Maximum number of server versions to run at once. When exceeded, the
+	/// least recently used version is stopped.
+	#[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+	pub max_running_versions: Option<u32>,
+	/// Redirect requests for server versions that aren't running to the
+	/// latest version, instead of starting them.
+	#[clap(long)]
+	pub redirect_old_versions: bool,
+	/// File containing a password hash created with `serve-web hash-password`.
+	/// When set, users must log in with the password to use the web UI.
+	#[clap(long)]
+	pub password_hash_file: Option<String>,
+	/// Password hash created with `serve-web hash-password`, as an alternative to `--password-hash-file`.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_SERVE_WEB_PASSWORD_HASH",
+		hide_env_values = true,
+		conflicts_with = "password_hash_file"
+	)]
+	pub password_hash: Option<String>,
+	/// Number of seconds a login session lasts.
+	#[clap(long, default_value_t = 60 * 60 * 24 * 7)]
+	pub session_timeout: u64,
+	/// File of `user:hash` lines, with hashes created by `serve-web hash-password`.
+	/// When set, users must log in with their user name and password.
+	#[clap(long, conflicts_with_all = ["password_hash", "password_hash_file"])]
+	pub accounts_file: Option<String>,
+	/// Name of a header set by a trusted reverse proxy, such as `X-Forwarded-User`,
+	/// that identifies the user. Only use this if the web server can't be reached
+	/// except through the proxy, since clients could otherwise set the header themselves.
+	#[clap(long, requires = "users_root", conflicts_with_all = ["password_hash", "password_hash_file", "accounts_file"])]
+	pub trusted_user_header: Option<String>,
+	/// Address of a trusted reverse proxy. Failed authentication attempts from
+	/// it are counted against the client in its `X-Forwarded-For` header, so
+	/// that one client can't lock out everyone behind the proxy. Can be given
+	/// multiple times.
+	#[clap(long, value_name = "IP")]
+	pub trusted_proxy: Vec<std::net::IpAddr>,
+	/// Directory under which each user gets their own user data and extensions
+	/// directories. Requires users to be identified by `--accounts-file` or
+	/// `--trusted-user-header`.
+	#[clap(long, conflicts_with_all = ["user_data_dir", "extensions_dir"])]
+	pub users_root: Option<String>,
+	/// Check for new releases every given number of seconds in the background,
+	/// downloading them before they're served to new clients.
+	#[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["commit", "version", "server_archive"])]
+	pub predownload_interval: Option<u64>,
+	/// Don't compress proxied responses with gzip or brotli. Useful when a reverse proxy
+	/// in front of the server already compresses them.
+	#[clap(long)]
+	pub without_compression: bool,
+	/// Serve the metrics endpoint without requiring the connection token as a
+	/// bearer token. Servers of different users are reported together, so user
+	/// names aren't exposed. Only use this if the endpoint can't be reached by untrusted clients.
+	#[clap(long)]
+	pub metrics_without_auth: bool,
+	/// File to write an access log of requests to. It's rotated once it
+	/// reaches `--access-log-max-size`.
+	#[clap(long)]
+	pub access_log: Option<String>,
+	/// Format of the access log. `clf` is the Common Log Format followed by
+	/// the request duration in milliseconds and the commit that served it.
+	#[clap(long, value_enum, default_value_t = AccessLogFormat::Clf, requires = "access_log")]
+	pub access_log_format: AccessLogFormat,
+	/// Size in megabytes at which the access log is rotated.
+	#[clap(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..), requires = "access_log")]
+	pub access_log_max_size: u64,
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+	match s.split_once('=') {
+		Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+		_ => Err(format!("expected KEY=VALUE, got '{}'", s)),
+	}
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum AccessLogFormat {
+	Clf,
+	Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ServeWebSubcommand {
+	/// Prompts for a password and prints its hash, for use with `--password-hash-file`.
+	HashPassword,
+
+	/// Lists the server versions of a running web server.
+	List,
+
+	/// Stops a running server version.
+	Stop(ServeWebVersionArgs),
+
+	/// Downloads a server version into the cache of a running web server.
+	Download(ServeWebVersionArgs),
+
+	/// Removes a server version from the cache of a running web server.
+	Evict(ServeWebVersionArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeWebVersionArgs {
+	/// Commit of the server version.
+	pub commit: String,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -228,6 +389,10 @@ pub struct CommandShellArgs {
 	/// Require the given token string to be given in the handshake.
 	#[clap(long)]
 	pub require_token: Option<String>,
+	/// Exit after this many seconds without any connections. Useful with
+	/// systemd socket activation, which starts the command shell again on demand.
+	#[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+	pub idle_exit_timeout: Option<u64>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -9,63 +9,91 @@ use std::fs;
 use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use const_format::concatcp;
+use futures::future::BoxFuture;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::pin;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
 
 use crate::async_pipe::{
-	get_socket_name, get_socket_rw_stream, listen_socket_rw_stream, AsyncPipe,
+	get_socket_name, get_socket_rw_stream, listen_socket_rw_stream, AsyncPipe, AsyncPipeListener,
 };
 use crate::constants::VSCODE_CLI_QUALITY;
 use crate::download_cache::DownloadCache;
 use crate::log;
 use crate::options::Quality;
 use crate::state::{LauncherPaths, PersistedState};
-use crate::tunnels::shutdown_signal::ShutdownRequest;
+use crate::tunnels::shutdown_signal::{ShutdownRequest, ShutdownSignal};
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
+use crate::util::auth_lockout::{self, AuthLockout};
+use crate::util::command::kill_tree;
 use crate::util::errors::AnyError;
-use crate::util::http::{self, ReqwestSimpleHttp};
+use crate::util::http::ReqwestSimpleHttp;
+use crate::util::input::prompt_password_with_confirmation;
 use crate::util::io::SilentCopyProgress;
+use crate::util::socket_activation::{self, ActivatedListener};
 use crate::util::sync::{new_barrier, Barrier, BarrierOpener};
 use crate::{
 	tunnels::legal,
 	util::{errors::CodeError, prereqs::PreReqChecker},
 };
 
-use super::{args::ServeWebArgs, CommandContext};
+use super::{
+	args::{ServeWebArgs, ServeWebSubcommand},
+	CommandContext,
+};
+
+mod access_log;
+mod admin;
+mod auth;
+mod compression;
+mod health;
+mod metrics;
+mod offline;
+mod pool;
+mod tls;
+mod users;
 
 /// Length of a commit hash, for validation
 const COMMIT_HASH_LEN: usize = 40;
-/// Number of seconds where, if there's no connections to a VS Code server,
-/// the server is shut down.
-const SERVER_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
 /// Number of seconds in which the server times out when there is a connection
 /// (should be large enough to basically never happen)
-const SERVER_ACTIVE_TIMEOUT_SECS: u64 = SERVER_IDLE_TIMEOUT_SECS * 24 * 30 * 12;
+const SERVER_ACTIVE_TIMEOUT_SECS: u64 = 60 * 60 * 24 * 30 * 12;
 /// How long to cache the "latest" version we get from the update service.
 const RELEASE_CACHE_SECS: u64 = 60 * 60;
+/// Number of seconds a server gets to exit after SIGTERM before it's killed.
+const SERVER_TERMINATE_TIMEOUT_SECS: u64 = 5;
+/// How often to check whether connections have closed while shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for servers to exit when shutting down before their
+/// process trees are killed.
+const SHUTDOWN_SERVERS_TIMEOUT: Duration = Duration::from_secs(SERVER_TERMINATE_TIMEOUT_SECS * 2);
+/// How often to check whether serve-web is idle, for `--idle-exit-timeout`.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// File in the CLI data dir where the connection token is stored.
+const CONNECTION_TOKEN_FILE_NAME: &str = "serve-web-token";
 
 /// Number of bytes for the secret keys. See workbench.ts for their usage.
 const SECRET_KEY_BYTES: usize = 32;
 /// Path to mint the key combining server and client parts.
 const SECRET_KEY_MINT_PATH: &str = "/_vscode-cli/mint-key";
+/// Path where Prometheus-style metrics are served. Requests must have the
+/// connection token as a bearer token, unless `--metrics-without-auth` is set.
+const METRICS_PATH: &str = "/_vscode-cli/metrics";
 /// Cookie set to the `SECRET_KEY_MINT_PATH`
 const PATH_COOKIE_NAME: &str = "vscode-secret-key-path";
-/// Cookie set to the `SECRET_KEY_MINT_PATH`
-const PATH_COOKIE_VALUE: &str = concatcp!(
-	PATH_COOKIE_NAME,
-	"=",
-	SECRET_KEY_MINT_PATH,
-	"; SameSite=Strict; Path=/"
-);
+/// Cookie the server accepts the connection token in.
+const CONNECTION_TOKEN_COOKIE_NAME: &str = "vscode-tkn";
 /// HTTP-only cookie where the client's secret half is stored.
 const SECRET_KEY_COOKIE_NAME: &str = "vscode-cli-secret-half";
 
@@ -78,91 +106,417 @@ pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i3
 	legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
 
 	let platform: crate::update_service::Platform = PreReqChecker::new().verify().await?;
+	if let Some(archive) = &args.server_archive {
+		let cache = DownloadCache::new(ctx.paths.web_server_storage());
+		let (quality, commit) =
+			offline::seed_cache_from_archive(&ctx.log, &cache, Path::new(archive)).await?;
+		args.quality = Some(quality);
+		args.commit = Some(commit);
+	}
+	if let Some(commit) = &args.commit {
+		if !is_commit_hash(commit) {
+			return Err(CodeError::InvalidCommitHash(commit.clone()).into());
+		}
+	}
+	if args.users_root.is_some()
+		&& args.accounts_file.is_none()
+		&& args.trusted_user_header.is_none()
+	{
+		return Err(CodeError::MissingUserIdentity.into());
+	}
+
 	if !args.without_connection_token {
 		// Ensure there's a defined connection token, since if multiple server versions
 		// are excuted, they will need to have a single shared token.
-		let token_path = ctx.paths.root().join("serve-web-token");
+		let token_path = ctx.paths.root().join(CONNECTION_TOKEN_FILE_NAME);
 		let token = mint_connection_token(&token_path, args.connection_token.clone())
 			.map_err(CodeError::CouldNotCreateConnectionTokenFile)?;
 		args.connection_token = Some(token);
 		args.connection_token_file = Some(token_path.to_string_lossy().to_string());
 	}
 
+	let auth = auth::Authenticator::from_args(&args)?.map(Arc::new);
+	let access_log = access_log::AccessLog::from_args(&args)?;
 	let cm = ConnectionManager::new(&ctx, platform, args.clone());
+	if let Some(secs) = args.predownload_interval {
+		tokio::spawn(cm.clone().predownload_releases(Duration::from_secs(secs)));
+	}
 	let key = get_server_key_half(&ctx.paths);
-	let make_svc = move || {
+	let svc_cm = cm.clone();
+	let make_svc = move |client: Option<SocketAddr>| {
 		let ctx = HandleContext {
-			cm: cm.clone(),
-			log: cm.log.clone(),
+			cm: svc_cm.clone(),
+			log: svc_cm.log.clone(),
 			server_secret_key: key.clone(),
+			auth: auth.clone(),
+			access_log: access_log.clone(),
+			client,
+			_connection: Arc::new(svc_cm.track_connection()),
 		};
 		let service = service_fn(move |req| handle(ctx.clone(), req));
 		async move { Ok::<_, Infallible>(service) }
 	};
 
-	let mut shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
-	let r = if let Some(s) = args.socket_path {
-		let s = PathBuf::from(&s);
-		let socket = listen_socket_rw_stream(&s).await?;
-		ctx.log
-			.result(format!("Web UI available on {}", s.display()));
-		let r = Server::builder(socket.into_pollable())
-			.serve(make_service_fn(|_| make_svc()))
-			.with_graceful_shutdown(async {
-				let _ = shutdown.wait().await;
-			})
-			.await;
-		let _ = std::fs::remove_file(&s); // cleanup
-		r
-	} else {
-		let addr: SocketAddr = match &args.host {
-			Some(h) => {
-				SocketAddr::new(h.parse().map_err(CodeError::InvalidHostAddress)?, args.port)
-			}
-			None => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), args.port),
+	let mut shutdown_requests = vec![ShutdownRequest::CtrlC, ShutdownRequest::Terminated];
+	if let Some(secs) = args.idle_exit_timeout {
+		let (idle, idle_opener) = new_barrier();
+		let cm = cm.clone();
+		tokio::spawn(async move {
+			cm.wait_until_idle(Duration::from_secs(secs)).await;
+			idle_opener.open(ShutdownSignal::IdleTimeout);
+		});
+		shutdown_requests.push(ShutdownRequest::Derived(Box::new(idle)));
+	}
+	let shutdown = ShutdownRequest::create_rx(shutdown_requests);
+	let base_path = normalize_base_path(args.server_base_path.as_deref());
+	let listening = |scheme: &str, addr: SocketAddr| {
+		let mut listening = format!("Web UI available at {}://{}{}", scheme, addr, base_path);
+		if let (Some(ct), false) = (&args.connection_token, injects_connection_token(&args)) {
+			listening.push_str(&format!("?tkn={}", ct));
+		}
+		listening
+	};
+
+	let tls = tls::TlsConfig::from_args(&ctx.log, &ctx.paths, &args).await?;
+	let mut servers: Vec<BoxFuture<'static, Result<(), hyper::Error>>> = Vec::new();
+	for listener in listeners(&ctx.log, &args).await? {
+		let make_svc = make_svc.clone();
+		let mut shutdown = shutdown.clone();
+		let graceful = async move {
+			let _ = shutdown.wait().await;
 		};
 
-		let mut listening = format!("Web UI available at http://{}", addr);
-		if let Some(ct) = args.connection_token {
-			listening.push_str(&format!("?tkn={}", ct));
+		match (listener, &tls) {
+			(
+				Listener::Socket {
+					listener,
+					path,
+					owned,
+				},
+				_,
+			) => {
+				ctx.log
+					.result(format!("Web UI available on {}", path.display()));
+				servers.push(Box::pin(async move {
+					let r = Server::builder(listener.into_pollable())
+						.serve(make_service_fn(move |_| make_svc(None)))
+						.with_graceful_shutdown(graceful)
+						.await;
+					if owned {
+						let _ = fs::remove_file(&path); // cleanup
+					}
+					r
+				}));
+			}
+			(Listener::Tcp(listener), Some(tls)) => {
+				let incoming = tls.listen(listener)?;
+				ctx.log.result(listening("https", incoming.local_addr()));
+				servers.push(Box::pin(
+					Server::builder(incoming)
+						.serve(make_service_fn(move |s: &tls::TlsStream| {
+							make_svc(s.get_ref().get_ref().get_ref().peer_addr().ok())
+						}))
+						.with_graceful_shutdown(graceful),
+				));
+			}
+			(Listener::Tcp(listener), None) => {
+				let builder =
+					Server::from_tcp(listener).map_err(CodeError::CouldNotListenOnInterface)?;
+				let server = builder.serve(make_service_fn(move |s: &AddrStream| {
+					make_svc(Some(s.remote_addr()))
+				}));
+				ctx.log.result(listening("http", server.local_addr()));
+				servers.push(Box::pin(server.with_graceful_shutdown(graceful)));
+			}
 		}
-		ctx.log.result(listening);
+	}
 
-		Server::bind(&addr)
-			.serve(make_service_fn(|_| make_svc()))
-			.with_graceful_shutdown(async {
-				let _ = shutdown.wait().await;
-			})
-			.await
-	};
+	let results = futures::future::join_all(servers).await;
 
-	r.map_err(CodeError::CouldNotListenOnInterface)?;
+	if let Some(signal) = shutdown.try_get() {
+		info!(ctx.log, "{}, shutting down", signal);
+	}
+	cm.shutdown(Duration::from_secs(args.shutdown_grace_period))
+		.await;
+
+	for r in results {
+		r.map_err(CodeError::CouldNotListenOnInterface)?;
+	}
 
 	Ok(0)
 }
 
+/// Address the web server listens on.
+#[derive(Debug, PartialEq, Eq)]
+enum ListenAddress {
+	Tcp(SocketAddr),
+	/// Unix socket, or named pipe on Windows.
+	Socket(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+	type Err = CodeError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(addr) = s.strip_prefix("tcp://") {
+			if let Ok(addr) = addr.parse() {
+				return Ok(ListenAddress::Tcp(addr));
+			}
+		} else if let Some(path) = s.strip_prefix("unix://") {
+			if !path.is_empty() {
+				return Ok(ListenAddress::Socket(PathBuf::from(path)));
+			}
+		}
+
+		Err(CodeError::InvalidListenAddress(s.to_string()))
+	}
+}
+
+/// Socket the web server accepts connections on.
+enum Listener {
+	Tcp(std::net::TcpListener),
+	/// Unix socket or named pipe. Sockets that are `owned` were created by
+	/// serve-web, and are removed when it exits.
+	Socket {
+		listener: AsyncPipeListener,
+		path: PathBuf,
+		owned: bool,
+	},
+}
+
+/// Gets the sockets passed with socket activation, or otherwise binds to the
+/// addresses to listen on.
+async fn listeners(log: &log::Logger, args: &ServeWebArgs) -> Result<Vec<Listener>, CodeError> {
+	let activated = socket_activation::take_listeners(log);
+	if !activated.is_empty() {
+		info!(log, "Using {} socket(s) passed by systemd", activated.len());
+		return activated
+			.into_iter()
+			.map(|l| match l {
+				ActivatedListener::Tcp(l) => Ok(Listener::Tcp(l)),
+				#[cfg(unix)]
+				ActivatedListener::Unix(l) => {
+					let path = l
+						.local_addr()
+						.ok()
+						.and_then(|a| a.as_pathname().map(Path::to_owned))
+						.unwrap_or_default();
+					Ok(Listener::Socket {
+						listener: AsyncPipeListener::from_std(l)?,
+						path,
+						owned: false,
+					})
+				}
+			})
+			.collect();
+	}
+
+	let mut listeners = Vec::new();
+	for address in listen_addresses(args)? {
+		listeners.push(match address {
+			ListenAddress::Tcp(addr) => Listener::Tcp(
+				std::net::TcpListener::bind(addr).map_err(CodeError::CouldNotBindAddress)?,
+			),
+			ListenAddress::Socket(path) => Listener::Socket {
+				listener: listen_socket_rw_stream(&path).await?,
+				path,
+				owned: true,
+			},
+		});
+	}
+
+	Ok(listeners)
+}
+
+/// Gets the addresses to listen on from `--listen`, or from the socket path
+/// or host and port if it wasn't given.
+fn listen_addresses(args: &ServeWebArgs) -> Result<Vec<ListenAddress>, CodeError> {
+	if !args.listen.is_empty() {
+		return args.listen.iter().map(|l| l.parse()).collect();
+	}
+
+	if let Some(s) = &args.socket_path {
+		return Ok(vec![ListenAddress::Socket(PathBuf::from(s))]);
+	}
+
+	let host = match &args.host {
+		Some(h) => h.parse().map_err(CodeError::InvalidHostAddress)?,
+		None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+	};
+	Ok(vec![ListenAddress::Tcp(SocketAddr::new(host, args.port))])
+}
+
+/// Runs a `serve-web` subcommand. Most of these are admin commands run
+/// against a running `serve-web` instance.
+pub async fn subcommand(
+	ctx: CommandContext,
+	args: ServeWebArgs,
+	command: ServeWebSubcommand,
+) -> Result<i32, AnyError> {
+	match command {
+		ServeWebSubcommand::HashPassword => {
+			let password = prompt_password_with_confirmation("Password")?;
+			ctx.log
+				.result(auth::PasswordHash::new(&password).to_string());
+			Ok(0)
+		}
+		command => admin::run_command(ctx, args, command).await,
+	}
+}
+
 #[derive(Clone)]
 struct HandleContext {
 	cm: Arc<ConnectionManager>,
 	log: log::Logger,
 	server_secret_key: SecretKeyPart,
+	auth: Option<Arc<auth::Authenticator>>,
+	access_log: Option<Arc<access_log::AccessLog>>,
+	/// Address of the client, when connected over TCP.
+	client: Option<SocketAddr>,
+	/// Counts the client's connection as open until all requests are done.
+	_connection: Arc<OpenConnection>,
+}
+
+/// Open connection to serve-web, counted until dropped.
+struct OpenConnection(Arc<ConnectionManager>);
+
+impl Drop for OpenConnection {
+	fn drop(&mut self) {
+		self.0.open_connections.fetch_sub(1, Ordering::SeqCst);
+		*self.0.last_active.lock().unwrap() = Instant::now();
+	}
 }
 
 /// Handler function for an inbound request
 async fn handle(ctx: HandleContext, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+	let access_log = match ctx.access_log.clone() {
+		Some(l) => l,
+		None => return Ok(handle_request(ctx, req).await),
+	};
+
+	let info = access_log::RequestInfo {
+		client: ctx.client,
+		method: req.method().clone(),
+		path: req.uri().path().to_string(),
+		version: req.version(),
+		started_at: Instant::now(),
+		time: chrono::Local::now(),
+	};
+	let res = handle_request(ctx, req).await;
+	Ok(access_log.record(info, res))
+}
+
+async fn handle_request(ctx: HandleContext, req: Request<Body>) -> Response<Body> {
 	let client_key_half = get_client_key_half(&req);
-	let mut res = match req.uri().path() {
+	// Routing is done on the path without the base path, but requests are
+	// forwarded as-is since the server is also given the base path.
+	let path = match strip_base_path(&ctx.cm.base_path, req.uri().path()) {
+		Some(p) => p.to_string(),
+		None => return response::not_found(),
+	};
+	let cm = ctx.cm.clone();
+	let client = auth_lockout::client_address(
+		ctx.client.map(|c| c.ip()),
+		req.headers()
+			.get_all("X-Forwarded-For")
+			.iter()
+			.filter_map(|h| h.to_str().ok()),
+		&ctx.cm.args.trusted_proxy,
+	);
+	// probes aren't authenticated, so there's no need to lock them out
+	if !matches!(path.as_str(), health::HEALTHZ_PATH | health::READYZ_PATH) {
+		if let Some(remaining) = cm.auth_lockout.locked_out_for(client) {
+			return response::locked_out(remaining);
+		}
+	}
+
+	let auth = ctx.auth.clone();
+	let mut res = match (path.as_str(), auth) {
+		(auth::LOGIN_PATH, Some(a)) => a.handle_login(req).await,
+		(auth::LOGOUT_PATH, Some(a)) => a.handle_logout(req),
+		(METRICS_PATH, _) => handle_metrics(ctx, &req),
+		(health::HEALTHZ_PATH, _) => health::handle_healthz(&ctx.cm),
+		(health::READYZ_PATH, _) => health::handle_readyz(&ctx.cm).await,
+		(p, _) if p.starts_with(admin::ADMIN_PATH_PREFIX) => {
+			admin::handle(&ctx.cm, &p[admin::ADMIN_PATH_PREFIX.len()..], req).await
+		}
+		(_, Some(a)) => match a.get_session(&req) {
+			Some(session) => handle_user(ctx, &path, req, session.user).await,
+			None => a.login_required(&req),
+		},
+		(_, None) => match &ctx.cm.args.trusted_user_header {
+			Some(header) => match users::user_from_header(&req, header) {
+				Some(user) => handle_user(ctx, &path, req, Some(user)).await,
+				None => response::missing_user(),
+			},
+			None => handle_user(ctx, &path, req, None).await,
+		},
+	};
+
+	match res.extensions().get::<auth::AuthAttempt>() {
+		Some(auth::AuthAttempt::Failed) => {
+			cm.auth_lockout.record_failure(client);
+		}
+		Some(auth::AuthAttempt::Succeeded) => cm.auth_lockout.record_success(client),
+		None => {}
+	}
+
+	append_secret_headers(&cm.base_path, &mut res, &client_key_half);
+
+	res
+}
+
+/// Handles a request from an authenticated user, if any.
+async fn handle_user(
+	ctx: HandleContext,
+	path: &str,
+	req: Request<Body>,
+	user: Option<String>,
+) -> Response<Body> {
+	let logged_user = user.clone().map(access_log::RequestUser);
+	// users only get their own servers when there's somewhere to put their data
+	let user = user.filter(|_| ctx.cm.args.users_root.is_some());
+	let mut res = match path {
 		SECRET_KEY_MINT_PATH => handle_secret_mint(ctx, req),
-		_ => handle_proxied(ctx, req).await,
+		_ => handle_proxied(ctx, path, req, user).await,
 	};
 
-	append_secret_headers(&mut res, &client_key_half);
+	if let Some(u) = logged_user {
+		res.extensions_mut().insert(u);
+	}
+	res
+}
 
-	Ok(res)
+/// Gets whether users authenticate with the CLI rather than the connection
+/// token. The token is then given to the server by `handle_proxied`.
+fn injects_connection_token(args: &ServeWebArgs) -> bool {
+	args.password_hash.is_some()
+		|| args.password_hash_file.is_some()
+		|| args.accounts_file.is_some()
+		|| args.trusted_user_header.is_some()
 }
 
-async fn handle_proxied(ctx: HandleContext, req: Request<Body>) -> Response<Body> {
-	let release = if let Some((r, _)) = get_release_from_path(req.uri().path(), ctx.cm.platform) {
+async fn handle_proxied(
+	ctx: HandleContext,
+	path: &str,
+	mut req: Request<Body>,
+	user: Option<String>,
+) -> Response<Body> {
+	let release = if let Some((r, remaining)) = get_release_from_path(path, ctx.cm.platform) {
+		if ctx.cm.args.redirect_old_versions && !ctx.cm.is_version_active(&r, user.as_ref()) {
+			match ctx.cm.get_latest_release().await {
+				Ok(latest) if latest.commit != r.commit => {
+					return response::redirect_to_release(
+						&ctx.cm.base_path,
+						&latest,
+						&remaining,
+						req.uri().query(),
+					);
+				}
+				Ok(_) => {}
+				Err(e) => warning!(ctx.log, "error getting latest version to redirect: {}", e),
+			}
+		}
 		r
 	} else {
 		match ctx.cm.get_latest_release().await {
@@ -174,17 +528,78 @@ async fn handle_proxied(ctx: HandleContext, req: Request<Body>) -> Response<Body
 		}
 	};
 
-	match ctx.cm.get_connection(release).await {
-		Ok(rw) => {
-			if req.headers().contains_key(hyper::header::UPGRADE) {
-				forward_ws_req_to_server(ctx.log.clone(), rw, req).await
-			} else {
-				forward_http_req_to_server(rw, req).await
+	// Logged in users don't have the connection token, so give it to the server
+	// in the cookie that it accepts in place of the `tkn` query parameter.
+	let injects_token = injects_connection_token(&ctx.cm.args);
+	let presented_token = !injects_token && has_connection_token(&req);
+	if let (true, Some(ct)) = (injects_token, &ctx.cm.args.connection_token) {
+		if let Ok(v) = format!("{}={}", CONNECTION_TOKEN_COOKIE_NAME, ct).parse() {
+			req.headers_mut().append(hyper::header::COOKIE, v);
+		}
+	}
+
+	let commit = access_log::ServedCommit(release.commit.clone());
+	let (socket_path, handle) = match ctx.cm.get_connection(release, user).await {
+		Ok(c) => c,
+		Err(CodeError::ServerNotYetDownloaded) => return response::wait_for_download(),
+		Err(e) => return response::code_err(e),
+	};
+
+	let mut res = if req.headers().contains_key(hyper::header::UPGRADE) {
+		match get_socket_rw_stream(&socket_path).await {
+			Ok(rw) => {
+				forward_ws_req_to_server(ctx.log.clone(), ctx.cm.metrics.clone(), (rw, handle), req)
+					.await
 			}
+			Err(e) => response::code_err(e),
+		}
+	} else if ctx.cm.args.without_compression {
+		ctx.cm.metrics.record_proxied_request();
+		forward_http_req_to_server(&ctx.cm.pool, socket_path, handle, req).await
+	} else {
+		ctx.cm.metrics.record_proxied_request();
+		let negotiation = compression::negotiate(&mut req);
+		let res = forward_http_req_to_server(&ctx.cm.pool, socket_path, handle, req).await;
+		compression::compress(&negotiation, res)
+	};
+
+	// the server rejects requests without a valid connection token. Requests
+	// without any token, such as for the favicon, aren't counted as attempts.
+	if presented_token {
+		let attempt = match res.status() {
+			hyper::StatusCode::FORBIDDEN => auth::AuthAttempt::Failed,
+			_ => auth::AuthAttempt::Succeeded,
+		};
+		res.extensions_mut().insert(attempt);
+	}
+	res.extensions_mut().insert(commit);
+	res
+}
+
+fn handle_metrics(ctx: HandleContext, req: &Request<Body>) -> Response<Body> {
+	// metrics are behind the same bearer token as the admin API, unless opted out
+	let authenticated = !ctx.cm.args.metrics_without_auth;
+	if authenticated {
+		if let Some(res) = admin::authorize(&ctx.cm, req, "the metrics endpoint") {
+			return res;
 		}
-		Err(CodeError::ServerNotYetDownloaded) => response::wait_for_download(),
-		Err(e) => response::code_err(e),
 	}
+
+	let mut versions = ctx.cm.version_metrics();
+	if !authenticated {
+		versions = metrics::without_users(versions);
+	}
+
+	let body = ctx.cm.metrics.render(&versions, &ctx.cm.auth_lockout);
+	let mut res = Response::builder()
+		.status(200)
+		.header("Content-Type", metrics::CONTENT_TYPE)
+		.body(Body::from(body))
+		.unwrap();
+	if authenticated {
+		res.extensions_mut().insert(auth::AuthAttempt::Succeeded);
+	}
+	res
 }
 
 fn handle_secret_mint(ctx: HandleContext, req: Request<Body>) -> Response<Body> {
@@ -199,26 +614,69 @@ fn handle_secret_mint(ctx: HandleContext, req: Request<Body>) -> Response<Body>
 }
 
 /// Appends headers to response to maintain the secret storage of the workbench:
-/// sets the `PATH_COOKIE_VALUE` so workbench.ts knows about the 'mint' endpoint,
+/// sets the `PATH_COOKIE_NAME` so workbench.ts knows about the 'mint' endpoint,
 /// and maintains the http-only cookie the client will use for cookies.
-fn append_secret_headers(res: &mut Response<Body>, client_key_half: &SecretKeyPart) {
+fn append_secret_headers(
+	base_path: &str,
+	res: &mut Response<Body>,
+	client_key_half: &SecretKeyPart,
+) {
 	let headers = res.headers_mut();
 	headers.append(
 		hyper::header::SET_COOKIE,
-		PATH_COOKIE_VALUE.parse().unwrap(),
+		format!(
+			"{}={}{}; SameSite=Strict; Path={}",
+			PATH_COOKIE_NAME,
+			base_path,
+			SECRET_KEY_MINT_PATH,
+			cookie_path(base_path)
+		)
+		.parse()
+		.unwrap(),
 	);
 	headers.append(
 		hyper::header::SET_COOKIE,
 		format!(
-			"{}={}; SameSite=Strict; HttpOnly; Max-Age=2592000; Path=/",
+			"{}={}; SameSite=Strict; HttpOnly; Max-Age=2592000; Path={}",
 			SECRET_KEY_COOKIE_NAME,
-			client_key_half.encode()
+			client_key_half.encode(),
+			cookie_path(base_path)
 		)
 		.parse()
 		.unwrap(),
 	);
 }
 
+/// Normalizes the `--server-base-path` to start with a `/` and not end with
+/// one, such that it can be prepended to paths. The root is an empty string.
+fn normalize_base_path(base_path: Option<&str>) -> String {
+	let trimmed = base_path.unwrap_or_default().trim().trim_matches('/');
+	if trimmed.is_empty() {
+		String::new()
+	} else {
+		format!("/{}", trimmed)
+	}
+}
+
+/// Removes the normalized base path from the request path, returning None if
+/// the request is outside of the base path.
+fn strip_base_path<'a>(base_path: &str, path: &'a str) -> Option<&'a str> {
+	match path.strip_prefix(base_path)? {
+		"" => Some("/"),
+		p if p.starts_with('/') => Some(p),
+		_ => None,
+	}
+}
+
+/// Gets the `Path` attribute for cookies under the normalized base path.
+fn cookie_path(base_path: &str) -> &str {
+	if base_path.is_empty() {
+		"/"
+	} else {
+		base_path
+	}
+}
+
 /// Gets the release info from the VS Code path prefix, which is in the
 /// format `/<quality>-<commit>/...`
 fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, String)> {
@@ -232,6 +690,7 @@ fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, Str
 
 	let (quality_commit, remaining) = path.split_at(i);
 	let (quality, commit) = quality_commit.split_at(quality_commit_sep);
+	let commit = &commit[1..];
 
 	if !is_commit_hash(commit) {
 		return None;
@@ -239,7 +698,6 @@ fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, Str
 
 	Some((
 		Release {
-			// remember to trim off the leading '/' which is now part of th quality
 			quality: Quality::try_from(quality).ok()?,
 			commit: commit.to_string(),
 			platform,
@@ -250,29 +708,37 @@ fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, Str
 	))
 }
 
-/// Proxies the standard HTTP request to the async pipe, returning the piped response
+/// Proxies the standard HTTP request to the server on a pooled keep-alive
+/// connection, returning the piped response
 async fn forward_http_req_to_server(
-	(rw, handle): (AsyncPipe, ConnectionHandle),
+	pool: &Arc<pool::ConnectionPool>,
+	socket_path: PathBuf,
+	handle: ConnectionHandle,
 	req: Request<Body>,
 ) -> Response<Body> {
-	let (mut request_sender, connection) =
-		match hyper::client::conn::Builder::new().handshake(rw).await {
-			Ok(r) => r,
-			Err(e) => return response::connection_err(e),
-		};
-
-	tokio::spawn(connection);
+	let mut request_sender = match pool.take(&socket_path) {
+		Some(s) => s,
+		None => {
+			let rw = match get_socket_rw_stream(&socket_path).await {
+				Ok(rw) => rw,
+				Err(e) => return response::code_err(e),
+			};
+			match hyper::client::conn::Builder::new().handshake(rw).await {
+				Ok((s, connection)) => {
+					tokio::spawn(connection);
+					s
+				}
+				Err(e) => return response::connection_err(e),
+			}
+		}
+	};
 
 	let res = request_sender
 		.send_request(req)
 		.await
 		.unwrap_or_else(response::connection_err);
 
-	// technically, we should buffer the body into memory since it may not be
-	// read at this point, but because the keepalive time is very large
-	// there's not going to be responses that take hours to send and x
-	// cause us to kill the server before the response is sent
-	drop(handle);
+	pool.release(socket_path, request_sender, handle);
 
 	res
 }
@@ -280,6 +746,7 @@ async fn forward_http_req_to_server(
 /// Proxies the websocket request to the async pipe
 async fn forward_ws_req_to_server(
 	log: log::Logger,
+	metrics: Arc<metrics::Metrics>,
 	(rw, handle): (AsyncPipe, ConnectionHandle),
 	mut req: Request<Body>,
 ) -> Response<Body> {
@@ -323,6 +790,7 @@ async fn forward_ws_req_to_server(
 				(_, Err(e2)) => debug!(log, "server ({}) websocket upgrade failed", e2),
 				(Ok(mut s_req), Ok(mut s_res)) => {
 					trace!(log, "websocket upgrade succeeded");
+					let _guard = metrics.record_websocket();
 					let r = tokio::io::copy_bidirectional(&mut s_req, &mut s_res).await;
 					trace!(log, "websocket closed (error: {:?})", r.err());
 				}
@@ -335,11 +803,30 @@ async fn forward_ws_req_to_server(
 	proxied_res
 }
 
+/// Gets the quality as it appears in the server's path prefix.
+fn quality_path_segment(quality: Quality) -> &'static str {
+	match quality {
+		Quality::Insiders => "insider",
+		q => q.get_machine_name(),
+	}
+}
+
 /// Returns whether the string looks like a commit hash.
 fn is_commit_hash(s: &str) -> bool {
 	s.len() == COMMIT_HASH_LEN && s.chars().all(|c| c.is_ascii_hexdigit())
 }
 
+/// Gets whether the request has a connection token for the server, in the
+/// `tkn` query parameter or the cookie the server sets from it.
+fn has_connection_token(req: &Request<Body>) -> bool {
+	let in_query = req
+		.uri()
+		.query()
+		.map(|q| q.split('&').any(|p| p.split('=').next() == Some("tkn")))
+		.unwrap_or(false);
+	in_query || extract_cookie(req, CONNECTION_TOKEN_COOKIE_NAME).is_some()
+}
+
 /// Gets a cookie from the request by name.
 fn extract_cookie(req: &Request<Body>, name: &str) -> Option<String> {
 	for h in req.headers().get_all(hyper::header::COOKIE) {
@@ -441,6 +928,55 @@ mod response {
 			.unwrap()
 	}
 
+	pub fn redirect_to_release(
+		base_path: &str,
+		release: &Release,
+		remaining: &str,
+		query: Option<&str>,
+	) -> Response<Body> {
+		let mut location = format!(
+			"{}/{}-{}{}",
+			base_path,
+			quality_path_segment(release.quality),
+			release.commit,
+			if remaining.is_empty() { "/" } else { remaining }
+		);
+		if let Some(q) = query {
+			location.push('?');
+			location.push_str(q);
+		}
+
+		Response::builder()
+			.status(302)
+			.header("Location", location)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	pub fn not_found() -> Response<Body> {
+		Response::builder()
+			.status(404)
+			.body(Body::from("Not found"))
+			.unwrap()
+	}
+
+	pub fn locked_out(remaining: Duration) -> Response<Body> {
+		Response::builder()
+			.status(429)
+			.header("Retry-After", remaining.as_secs().max(1))
+			.body(Body::from(
+				"Too many failed authentication attempts, try again later",
+			))
+			.unwrap()
+	}
+
+	pub fn missing_user() -> Response<Body> {
+		Response::builder()
+			.status(401)
+			.body(Body::from("Missing or invalid user header"))
+			.unwrap()
+	}
+
 	pub fn secret_key(hash: Vec<u8>) -> Response<Body> {
 		Response::builder()
 			.status(200)
@@ -473,15 +1009,81 @@ impl Drop for ConnectionHandle {
 	}
 }
 
-type StartData = (PathBuf, Arc<tokio::sync::watch::Sender<usize>>);
+/// Server processes being started or running, so that shutting down can wait
+/// for them to exit.
+#[derive(Default)]
+struct ServerProcesses {
+	next_id: AtomicUsize,
+	pids: Mutex<HashMap<usize, Option<u32>>>,
+}
+
+impl ServerProcesses {
+	/// Tracks a server that's about to start until the returned value is dropped.
+	fn track(self: &Arc<Self>) -> ServerProcess {
+		let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+		self.pids.lock().unwrap().insert(id, None);
+		ServerProcess(self.clone(), id)
+	}
+
+	fn is_empty(&self) -> bool {
+		self.pids.lock().unwrap().is_empty()
+	}
+
+	fn pids(&self) -> Vec<u32> {
+		self.pids
+			.lock()
+			.unwrap()
+			.values()
+			.flatten()
+			.copied()
+			.collect()
+	}
+}
+
+/// Server process tracked in `ServerProcesses`, until dropped.
+struct ServerProcess(Arc<ServerProcesses>, usize);
+
+impl ServerProcess {
+	fn set_pid(&self, pid: Option<u32>) {
+		self.0.pids.lock().unwrap().insert(self.1, pid);
+	}
+}
+
+impl Drop for ServerProcess {
+	fn drop(&mut self) {
+		self.0.pids.lock().unwrap().remove(&self.1);
+	}
+}
+
+/// Data for a server version once it's started and accepting connections.
+#[derive(Clone)]
+struct StartData {
+	socket_path: PathBuf,
+	client_counter: Arc<tokio::sync::watch::Sender<usize>>,
+	pid: Option<u32>,
+	started_at: Instant,
+	/// Opened to stop the server.
+	stopper: BarrierOpener<()>,
+}
 
 /// State stored in the ConnectionManager for each server version.
 struct VersionState {
 	downloaded: bool,
+	/// Last time a connection was requested, for LRU eviction.
+	last_used: Instant,
 	socket_path: Barrier<Result<StartData, String>>,
 }
 
-type ConnectionStateMap = Arc<Mutex<HashMap<(Quality, String), VersionState>>>;
+/// Identifies a server in the ConnectionManager. Each user gets their own
+/// servers when running with `--users-root`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct VersionKey {
+	quality: Quality,
+	commit: String,
+	user: Option<String>,
+}
+
+type ConnectionStateMap = Arc<Mutex<HashMap<VersionKey, VersionState>>>;
 
 /// Manages the connections to running web UI instances. Multiple web servers
 /// can run concurrently, with routing based on the URL path.
@@ -491,23 +1093,45 @@ struct ConnectionManager {
 	args: ServeWebArgs,
 	/// Cache where servers are stored
 	cache: DownloadCache,
-	/// Mapping of (Quality, Commit) to the state each server is in
+	/// Mapping of (Quality, Commit, User) to the state each server is in
 	state: ConnectionStateMap,
 	/// Update service instance
 	update_service: UpdateService,
 	/// Cache of the latest released version, storing the time we checked as well
 	latest_version: tokio::sync::Mutex<Option<(Instant, Release)>>,
+	/// Counters reported on the `METRICS_PATH`
+	pub metrics: Arc<metrics::Metrics>,
+	/// Normalized `--server-base-path`, empty when served at the root
+	pub base_path: String,
+	/// Keep-alive connections for proxied HTTP requests
+	pub pool: Arc<pool::ConnectionPool>,
+	/// Failed authentication attempts, across logins, the admin API, and
+	/// connection tokens checked by the servers
+	pub auth_lockout: AuthLockout,
+	/// Number of open connections to serve-web
+	open_connections: AtomicUsize,
+	/// Last time a connection was open, for `--idle-exit-timeout`
+	last_active: Mutex<Instant>,
+	/// Opened when serve-web is shutting down, to stop all servers
+	shutdown: Barrier<()>,
+	shutdown_opener: BarrierOpener<()>,
+	/// Server processes that are starting or running
+	processes: Arc<ServerProcesses>,
 }
 
-fn key_for_release(release: &Release) -> (Quality, String) {
-	(release.quality, release.commit.clone())
+fn key_for_release(release: &Release, user: Option<&String>) -> VersionKey {
+	VersionKey {
+		quality: release.quality,
+		commit: release.commit.clone(),
+		user: user.cloned(),
+	}
 }
 
 impl ConnectionManager {
 	pub fn new(ctx: &CommandContext, platform: Platform, args: ServeWebArgs) -> Arc<Self> {
+		let (shutdown, shutdown_opener) = new_barrier();
 		Arc::new(Self {
 			platform,
-			args,
 			log: ctx.log.clone(),
 			cache: DownloadCache::new(ctx.paths.web_server_storage()),
 			update_service: UpdateService::new(
@@ -516,44 +1140,228 @@ impl ConnectionManager {
 			),
 			state: ConnectionStateMap::default(),
 			latest_version: tokio::sync::Mutex::default(),
+			metrics: Arc::default(),
+			base_path: normalize_base_path(args.server_base_path.as_deref()),
+			pool: pool::ConnectionPool::new(),
+			auth_lockout: AuthLockout::new(ctx.log.clone()),
+			open_connections: AtomicUsize::new(0),
+			last_active: Mutex::new(Instant::now()),
+			shutdown,
+			shutdown_opener,
+			processes: Arc::default(),
+			args,
 		})
 	}
 
-	/// Gets a connection to a server version
+	/// Counts a new connection to serve-web until the returned value is dropped.
+	fn track_connection(self: &Arc<Self>) -> OpenConnection {
+		self.open_connections.fetch_add(1, Ordering::SeqCst);
+		OpenConnection(self.clone())
+	}
+
+	/// Gets the number of open connections to the servers, such as websockets.
+	fn server_connections(&self) -> usize {
+		self.version_metrics().iter().map(|v| v.connections).sum()
+	}
+
+	/// Resolves once there have been no connections to serve-web or the
+	/// servers for the given duration.
+	pub async fn wait_until_idle(&self, timeout: Duration) {
+		loop {
+			tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+			let mut last_active = self.last_active.lock().unwrap();
+			if self.open_connections.load(Ordering::SeqCst) > 0 || self.server_connections() > 0 {
+				*last_active = Instant::now();
+			} else if last_active.elapsed() >= timeout {
+				return;
+			}
+		}
+	}
+
+	/// Waits up to the grace period for open connections to the servers to
+	/// close, then stops all servers and waits for them to exit, killing any
+	/// that don't exit in time. Versions that are still downloading aren't started.
+	pub async fn shutdown(&self, grace_period: Duration) {
+		let deadline = Instant::now() + grace_period;
+		loop {
+			let connections = self.server_connections();
+			if connections == 0 {
+				break;
+			}
+			if Instant::now() >= deadline {
+				info!(
+					self.log,
+					"Grace period elapsed with {} open connections, stopping servers", connections
+				);
+				break;
+			}
+			tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+		}
+
+		self.shutdown_opener.open(());
+		let deadline = Instant::now() + SHUTDOWN_SERVERS_TIMEOUT;
+		while !self.processes.is_empty() {
+			if Instant::now() >= deadline {
+				let pids = self.processes.pids();
+				warning!(
+					self.log,
+					"Servers did not exit while shutting down, killing processes {:?}",
+					pids
+				);
+				for pid in pids {
+					kill_tree(pid).await.ok();
+				}
+				break;
+			}
+			tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+		}
+	}
+
+	/// Gets the socket path of a server version, and a handle which counts
+	/// as a client of the server for as long as it's held.
 	pub async fn get_connection(
 		&self,
 		release: Release,
-	) -> Result<(AsyncPipe, ConnectionHandle), CodeError> {
-		// todo@connor4312: there is likely some performance benefit to
-		// implementing a 'keepalive' for these connections.
-		let (path, counter) = self.get_version_data(release).await?;
-		let handle = ConnectionHandle::new(counter);
-		let rw = get_socket_rw_stream(&path).await?;
-		Ok((rw, handle))
+		user: Option<String>,
+	) -> Result<(PathBuf, ConnectionHandle), CodeError> {
+		let data = self.get_version_data(release, user).await?;
+		let handle = ConnectionHandle::new(data.client_counter);
+		Ok((data.socket_path, handle))
+	}
+
+	/// Gets the state of each known server version for metrics.
+	pub fn version_metrics(&self) -> Vec<metrics::VersionMetrics> {
+		self.list_versions()
+			.into_iter()
+			.map(|v| metrics::VersionMetrics {
+				quality: v.quality,
+				commit: v.commit,
+				user: v.user,
+				running: v.started.is_some(),
+				connections: v
+					.started
+					.map(|d| *d.client_counter.borrow())
+					.unwrap_or_default(),
+			})
+			.collect()
+	}
+
+	/// Lists server versions which are downloading, starting, or running.
+	pub fn list_versions(&self) -> Vec<VersionSummary> {
+		let state = self.state.lock().unwrap();
+		state
+			.iter()
+			.map(|(key, s)| VersionSummary {
+				quality: key.quality,
+				commit: key.commit.clone(),
+				user: key.user.clone(),
+				downloaded: s.downloaded || s.socket_path.is_open(),
+				started: s.socket_path.try_get().and_then(|r| r.ok()),
+			})
+			.collect()
 	}
 
-	/// Gets the latest release for the CLI quality, caching its result for some
-	/// time to allow for fast loads.
+	/// Stops running servers for the commit, optionally filtered to a quality,
+	/// for all users. Returns whether any server was stopped.
+	pub fn stop_version(&self, quality: Option<Quality>, commit: &str) -> bool {
+		let mut stopped = false;
+		for v in self.list_versions() {
+			if v.commit != commit || quality.map(|q| q != v.quality).unwrap_or(false) {
+				continue;
+			}
+
+			if let Some(d) = v.started {
+				info!(self.log, "Stopping server {}", v.commit);
+				d.stopper.open(());
+				stopped = true;
+			}
+		}
+
+		stopped
+	}
+
+	/// Downloads a server version into the cache without starting it.
+	pub async fn download_to_cache(&self, release: Release) -> Result<PathBuf, CodeError> {
+		if let Some(p) = self.cache.exists(&release.commit) {
+			return Ok(p);
+		}
+		if self.args.server_archive.is_some() {
+			return Err(CodeError::ServerNotAvailableOffline(release.commit));
+		}
+		if self
+			.state
+			.lock()
+			.unwrap()
+			.keys()
+			.any(|k| k.commit == release.commit)
+		{
+			return Err(CodeError::ServerNotYetDownloaded);
+		}
+
+		Self::download_into_cache(
+			&self.log,
+			release,
+			self.update_service.clone(),
+			&self.cache,
+			&self.metrics,
+		)
+		.await
+		.map_err(|e| CodeError::ServerDownloadError(e.to_string()))
+	}
+
+	/// Removes a server version from the cache. Fails if the version is in use.
+	pub fn evict_version(&self, commit: &str) -> Result<(), CodeError> {
+		if self.list_versions().iter().any(|v| v.commit == commit) {
+			return Err(CodeError::ServerVersionInUse(commit.to_string()));
+		}
+
+		info!(self.log, "Removing server {} from the cache", commit);
+		self.cache
+			.delete(commit)
+			.map_err(|e| CodeError::ServerDownloadError(e.to_string()))
+	}
+
+	/// Gets the release to serve to new clients. This is the pinned commit or
+	/// version if one was given, or otherwise the latest release for the
+	/// quality, caching its result for some time to allow for fast loads.
 	pub async fn get_latest_release(&self) -> Result<Release, CodeError> {
+		let quality = self.get_quality()?;
+		if let Some(commit) = &self.args.commit {
+			return Ok(Release {
+				quality,
+				commit: commit.clone(),
+				platform: self.platform,
+				target: TargetKind::Web,
+				name: "".to_string(),
+			});
+		}
+
 		let mut latest = self.latest_version.lock().await;
 		let now = Instant::now();
 		if let Some((checked_at, release)) = &*latest {
-			if checked_at.elapsed() < Duration::from_secs(RELEASE_CACHE_SECS) {
+			// a pinned version always resolves to the same commit, and predownloaded
+			// releases are only replaced once the next one is ready
+			if self.args.version.is_some()
+				|| self.args.predownload_interval.is_some()
+				|| checked_at.elapsed() < Duration::from_secs(RELEASE_CACHE_SECS)
+			{
 				return Ok(release.clone());
 			}
 		}
 
-		let quality = VSCODE_CLI_QUALITY
-			.ok_or_else(|| CodeError::UpdatesNotConfigured("no configured quality"))
-			.and_then(|q| {
-				Quality::try_from(q).map_err(|_| CodeError::UpdatesNotConfigured("unknown quality"))
-			})?;
-
-		let release = self
-			.update_service
-			.get_latest_commit(self.platform, TargetKind::Web, quality)
-			.await
-			.map_err(|e| CodeError::UpdateCheckFailed(e.to_string()));
+		let release = match &self.args.version {
+			Some(version) => {
+				self.update_service
+					.get_release_by_semver_version(self.platform, TargetKind::Web, quality, version)
+					.await
+			}
+			None => {
+				self.update_service
+					.get_latest_commit(self.platform, TargetKind::Web, quality)
+					.await
+			}
+		}
+		.map_err(|e| CodeError::UpdateCheckFailed(e.to_string()));
 
 		// If the update service is unavailable and we have stale data, use that
 		if let (Err(e), Some((_, previous))) = (&release, &*latest) {
@@ -568,11 +1376,91 @@ impl ConnectionManager {
 		Ok(release)
 	}
 
+	/// Gets the commit served to new clients without checking for updates.
+	/// Returns None if it hasn't been resolved yet, or is being resolved.
+	pub fn peek_latest_commit(&self) -> Option<String> {
+		if let Some(commit) = &self.args.commit {
+			return Some(commit.clone());
+		}
+
+		let latest = self.latest_version.try_lock().ok()?;
+		latest.as_ref().map(|(_, r)| r.commit.clone())
+	}
+
+	/// Polls for new releases, downloading them into the cache and only then
+	/// making them the latest release, so that clients don't have to wait
+	/// for the download.
+	pub async fn predownload_releases(self: Arc<Self>, interval: Duration) {
+		let quality = match self.get_quality() {
+			Ok(q) => q,
+			Err(e) => {
+				warning!(self.log, "not predownloading releases: {}", e);
+				return;
+			}
+		};
+
+		let mut interval = tokio::time::interval(interval);
+		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+		loop {
+			interval.tick().await;
+
+			let release = match self
+				.update_service
+				.get_latest_commit(self.platform, TargetKind::Web, quality)
+				.await
+			{
+				Ok(r) => r,
+				Err(e) => {
+					warning!(self.log, "error checking for a new release: {}", e);
+					continue;
+				}
+			};
+
+			let is_current = self
+				.latest_version
+				.lock()
+				.await
+				.as_ref()
+				.map(|(_, r)| r.commit == release.commit)
+				.unwrap_or(false);
+			if is_current {
+				continue;
+			}
+
+			match self.download_to_cache(release.clone()).await {
+				Ok(_) => {
+					info!(self.log, "Release {} is ready and will be served", release);
+					*self.latest_version.lock().await = Some((Instant::now(), release));
+				}
+				// being downloaded for a client, try again on the next tick
+				Err(CodeError::ServerNotYetDownloaded) => {}
+				Err(e) => warning!(self.log, "error predownloading {}: {}", release, e),
+			}
+		}
+	}
+
+	/// Gets the quality to serve, from the args or the CLI's own quality.
+	fn get_quality(&self) -> Result<Quality, CodeError> {
+		if let Some(q) = self.args.quality {
+			return Ok(q);
+		}
+
+		VSCODE_CLI_QUALITY
+			.ok_or_else(|| CodeError::UpdatesNotConfigured("no configured quality"))
+			.and_then(|q| {
+				Quality::try_from(q).map_err(|_| CodeError::UpdatesNotConfigured("unknown quality"))
+			})
+	}
+
 	/// Gets the StartData for the a version of the VS Code server, triggering
 	/// download/start if necessary. It returns `CodeError::ServerNotYetDownloaded`
 	/// while the server is downloading, which is used to have a refresh loop on the page.
-	async fn get_version_data(&self, release: Release) -> Result<StartData, CodeError> {
-		self.get_version_data_inner(release)?
+	async fn get_version_data(
+		&self,
+		release: Release,
+		user: Option<String>,
+	) -> Result<StartData, CodeError> {
+		self.get_version_data_inner(release, user)?
 			.wait()
 			.await
 			.unwrap()
@@ -582,10 +1470,12 @@ impl ConnectionManager {
 	fn get_version_data_inner(
 		&self,
 		release: Release,
+		user: Option<String>,
 	) -> Result<Barrier<Result<StartData, String>>, CodeError> {
 		let mut state = self.state.lock().unwrap();
-		let key = key_for_release(&release);
+		let key = key_for_release(&release, user.as_ref());
 		if let Some(s) = state.get_mut(&key) {
+			s.last_used = Instant::now();
 			if !s.downloaded {
 				if s.socket_path.is_open() {
 					s.downloaded = true;
@@ -604,14 +1494,19 @@ impl ConnectionManager {
 			log: self.log.clone(),
 			opener,
 			release,
+			user,
+			shutdown: self.shutdown.clone(),
+			processes: self.processes.clone(),
 		};
 
 		if let Some(p) = self.cache.exists(&args.release.commit) {
+			self.stop_lru_versions(&state);
 			state.insert(
 				key.clone(),
 				VersionState {
 					socket_path: socket_path.clone(),
 					downloaded: true,
+					last_used: Instant::now(),
 				},
 			);
 
@@ -620,58 +1515,169 @@ impl ConnectionManager {
 				state_map_dup.lock().unwrap().remove(&key);
 			});
 			Ok(socket_path)
+		} else if self.args.server_archive.is_some() {
+			Err(CodeError::ServerNotAvailableOffline(args.release.commit))
+		} else if state
+			.iter()
+			.any(|(k, s)| k.commit == key.commit && !s.downloaded)
+		{
+			// another user's server is downloading this version; it can be
+			// started from the cache once that's done
+			Err(CodeError::ServerNotYetDownloaded)
 		} else {
+			self.stop_lru_versions(&state);
 			state.insert(
 				key.clone(),
 				VersionState {
 					socket_path,
 					downloaded: false,
+					last_used: Instant::now(),
 				},
 			);
 			let update_service = self.update_service.clone();
 			let cache = self.cache.clone();
+			let metrics = self.metrics.clone();
 			tokio::spawn(async move {
-				Self::download_version(args, update_service.clone(), cache.clone()).await;
+				Self::download_version(args, update_service, cache, metrics).await;
 				state_map_dup.lock().unwrap().remove(&key);
 			});
 			Err(CodeError::ServerNotYetDownloaded)
 		}
 	}
 
+	/// Stops the least recently used running versions to make room for a new
+	/// version, if a `max_running_versions` is configured. Versions that are
+	/// downloading or starting count toward the maximum, and versions with
+	/// open connections are never stopped, since clients on a websocket
+	/// don't update their last use.
+	fn stop_lru_versions(&self, state: &HashMap<VersionKey, VersionState>) {
+		let max = match self.args.max_running_versions {
+			Some(m) => m as usize,
+			None => return,
+		};
+
+		let is_stopping = |s: &VersionState| match s.socket_path.try_get() {
+			Some(Ok(d)) => d.stopper.is_open(),
+			_ => false,
+		};
+
+		// +1 for the version that's about to start
+		let active = state.values().filter(|s| !is_stopping(s)).count();
+		let excess = (active + 1).saturating_sub(max);
+		if excess == 0 {
+			return;
+		}
+
+		let mut idle: Vec<_> = state
+			.iter()
+			.filter_map(|(key, s)| {
+				let data = s.socket_path.try_get()?.ok()?;
+				if data.stopper.is_open() || *data.client_counter.borrow() > 0 {
+					return None;
+				}
+				Some((s.last_used, key, data))
+			})
+			.collect();
+
+		if idle.len() < excess {
+			warning!(
+				self.log,
+				"Exceeding the maximum of {} running versions, since the others are in use",
+				max
+			);
+		}
+
+		idle.sort_by_key(|(last_used, _, _)| *last_used);
+		for (_, key, data) in idle.into_iter().take(excess) {
+			info!(
+				self.log,
+				"Stopping least recently used server {} for user {} to stay within the maximum of {} running versions",
+				key.commit,
+				key.user.as_deref().unwrap_or("(default)"),
+				max
+			);
+			data.stopper.open(());
+		}
+	}
+
+	/// Gets whether the version is downloading, starting, or running.
+	pub fn is_version_active(&self, release: &Release, user: Option<&String>) -> bool {
+		self.state
+			.lock()
+			.unwrap()
+			.contains_key(&key_for_release(release, user))
+	}
+
 	/// Downloads a server version into the cache and starts it.
 	async fn download_version(
 		args: StartArgs,
 		update_service: UpdateService,
 		cache: DownloadCache,
+		metrics: Arc<metrics::Metrics>,
 	) {
-		let release_for_fut = args.release.clone();
-		let log_for_fut = args.log.clone();
-		let dir_fut = cache.create(&args.release.commit, |target_dir| async move {
+		let result = Self::download_into_cache(
+			&args.log,
+			args.release.clone(),
+			update_service,
+			&cache,
+			&metrics,
+		)
+		.await;
+
+		match result {
+			Err(e) => args.opener.open(Err(e.to_string())),
+			Ok(dir) => Self::start_version(args, dir).await,
+		}
+	}
+
+	/// Downloads a server version into the cache, returning its path.
+	async fn download_into_cache(
+		log: &log::Logger,
+		release: Release,
+		update_service: UpdateService,
+		cache: &DownloadCache,
+		metrics: &metrics::Metrics,
+	) -> Result<PathBuf, AnyError> {
+		let started_at = Instant::now();
+		let release_for_fut = release.clone();
+		let log_for_fut = log.clone();
+		let dir_fut = cache.create(&release.commit, |target_dir| async move {
 			info!(log_for_fut, "Downloading server {}", release_for_fut.commit);
 			let tmpdir = tempfile::tempdir().unwrap();
-			let response = update_service.get_download_stream(&release_for_fut).await?;
-
-			let name = response.url_path_basename().unwrap();
-			let archive_path = tmpdir.path().join(name);
-			http::download_into_file(
-				&archive_path,
-				log_for_fut.get_download_logger("Downloading server:"),
-				response,
-			)
-			.await?;
+			let archive_path = update_service
+				.download_release(
+					&release_for_fut,
+					tmpdir.path(),
+					log_for_fut.get_download_logger("Downloading server:"),
+				)
+				.await?;
 			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
 			Ok(())
 		});
 
-		match dir_fut.await {
-			Err(e) => args.opener.open(Err(e.to_string())),
-			Ok(dir) => Self::start_version(args, dir).await,
-		}
+		let result = dir_fut.await;
+		metrics.record_download(started_at.elapsed(), result.is_ok());
+		result
 	}
 
 	/// Starts a downloaded server that can be found in the given `path`.
 	async fn start_version(args: StartArgs, path: PathBuf) {
-		info!(args.log, "Starting server {}", args.release.commit);
+		// tracked before checking for shutdown, so shutting down either waits
+		// for this server or it's never started
+		let process = args.processes.track();
+		if args.shutdown.is_open() {
+			args.opener
+				.open(Err("serve-web is shutting down".to_string()));
+			return;
+		}
+
+		match &args.user {
+			Some(u) => info!(
+				args.log,
+				"Starting server {} for {}", args.release.commit, u
+			),
+			None => info!(args.log, "Starting server {}", args.release.commit),
+		}
 
 		let executable = path
 			.join("bin")
@@ -699,10 +1705,23 @@ impl ConnectionManager {
 		// License agreement already checked by the `server_web` function.
 		cmd.args(["--accept-server-license-terms"]);
 
+		let base_path = normalize_base_path(args.args.server_base_path.as_deref());
+		if !base_path.is_empty() {
+			cmd.arg("--server-base-path");
+			cmd.arg(base_path);
+		}
+
 		if let Some(a) = &args.args.server_data_dir {
 			cmd.arg("--server-data-dir");
 			cmd.arg(a);
 		}
+		if let (Some(root), Some(user)) = (&args.args.users_root, &args.user) {
+			let (user_data_dir, extensions_dir) = users::user_dirs(Path::new(root), user);
+			cmd.arg("--user-data-dir");
+			cmd.arg(user_data_dir);
+			cmd.arg("--extensions-dir");
+			cmd.arg(extensions_dir);
+		}
 		if let Some(a) = &args.args.user_data_dir {
 			cmd.arg("--user-data-dir");
 			cmd.arg(a);
@@ -720,9 +1739,11 @@ impl ConnectionManager {
 			cmd.arg("--connection-token-file");
 			cmd.arg(ct);
 		}
+		cmd.args(&args.args.server_arg);
 
 		// removed, otherwise the workbench will not be usable when running the CLI from sources.
 		cmd.env_remove("VSCODE_DEV");
+		cmd.envs(args.args.server_env.iter().cloned());
 
 		let mut child = match cmd.spawn() {
 			Ok(c) => c,
@@ -732,6 +1753,8 @@ impl ConnectionManager {
 			}
 		};
 
+		process.set_pid(child.id());
+
 		let (mut stdout, mut stderr) = (
 			BufReader::new(child.stdout.take().unwrap()).lines(),
 			BufReader::new(child.stderr.take().unwrap()).lines(),
@@ -739,9 +1762,20 @@ impl ConnectionManager {
 
 		// wrapped option to prove that we only use this once in the loop
 		let (counter_tx, mut counter_rx) = tokio::sync::watch::channel(0);
-		let mut opener = Some((args.opener, socket_path, Arc::new(counter_tx)));
+		let (mut stop_barrier, stopper) = new_barrier();
+		let mut shutdown = args.shutdown;
+		let mut opener = Some((
+			args.opener,
+			StartData {
+				socket_path: socket_path.clone(),
+				client_counter: Arc::new(counter_tx),
+				pid: child.id(),
+				started_at: Instant::now(),
+				stopper,
+			},
+		));
 		let commit_prefix = &args.release.commit[..7];
-		let kill_timer = tokio::time::sleep(Duration::from_secs(SERVER_IDLE_TIMEOUT_SECS));
+		let kill_timer = tokio::time::sleep(Duration::from_secs(args.args.idle_timeout));
 		pin!(kill_timer);
 
 		loop {
@@ -750,8 +1784,8 @@ impl ConnectionManager {
 					info!(args.log, "[{} stdout]: {}", commit_prefix, l);
 
 					if l.contains("Server bound to") {
-						if let Some((opener, path, counter_tx)) = opener.take() {
-							opener.open(Ok((path, counter_tx)));
+						if let Some((opener, data)) = opener.take() {
+							opener.open(Ok(data));
 						}
 					}
 				}
@@ -764,16 +1798,26 @@ impl ConnectionManager {
 						Err(_) => tokio::time::Instant::now(),
 						Ok(_) => {
 							if *counter_rx.borrow() == 0 {
-								tokio::time::Instant::now() + Duration::from_secs(SERVER_IDLE_TIMEOUT_SECS)
+								tokio::time::Instant::now() + Duration::from_secs(args.args.idle_timeout)
 							} else {
 								tokio::time::Instant::now() + Duration::from_secs(SERVER_ACTIVE_TIMEOUT_SECS)
 							}
 						}
 					});
 				}
+				Ok(_) = stop_barrier.wait() => {
+					info!(args.log, "[{} process]: stop requested, ending", commit_prefix);
+					terminate_server(&args.log, &mut child, commit_prefix).await;
+					break;
+				}
+				Ok(_) = shutdown.wait() => {
+					info!(args.log, "[{} process]: shutting down, ending", commit_prefix);
+					terminate_server(&args.log, &mut child, commit_prefix).await;
+					break;
+				}
 				_ = &mut kill_timer => {
 					info!(args.log, "[{} process]: idle timeout reached, ending", commit_prefix);
-					let _ = child.kill().await;
+					terminate_server(&args.log, &mut child, commit_prefix).await;
 					break;
 				}
 				e = child.wait() => {
@@ -782,14 +1826,128 @@ impl ConnectionManager {
 				}
 			}
 		}
+
+		let _ = fs::remove_file(&socket_path); // cleanup
+	}
+}
+
+/// Parses `serve-web` arguments for tests.
+#[cfg(test)]
+fn test_args(args: &[&str]) -> ServeWebArgs {
+	#[derive(clap::Parser)]
+	struct Cli {
+		#[clap(flatten)]
+		args: ServeWebArgs,
+	}
+
+	let args = std::iter::once("serve-web").chain(args.iter().copied());
+	<Cli as clap::Parser>::parse_from(args).args
+}
+
+#[cfg(test)]
+impl ConnectionManager {
+	/// Creates a connection manager with the given `serve-web` arguments,
+	/// storing servers in the returned temporary directory.
+	fn new_test(args: &[&str]) -> (Arc<Self>, tempfile::TempDir) {
+		let args = test_args(args);
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = CommandContext {
+			log: log::Logger::test(),
+			paths: LauncherPaths::new_without_replacements(dir.path().to_owned()),
+			args: Default::default(),
+			http: reqwest::Client::new(),
+		};
+		(Self::new(&ctx, Platform::LinuxX64, args), dir)
+	}
+
+	/// Adds the commit to the download cache, as if it had been downloaded.
+	fn insert_test_download(&self, commit: &str) {
+		fs::create_dir_all(self.cache.path().join(commit)).unwrap();
+		self.cache.exists(commit);
+	}
+
+	/// Adds a server version which is downloading, or running with the given
+	/// client counter.
+	fn insert_test_version(
+		&self,
+		commit: &str,
+		running: Option<Arc<tokio::sync::watch::Sender<usize>>>,
+	) {
+		let (socket_path, opener) = new_barrier();
+		if let Some(client_counter) = running {
+			opener.open(Ok(StartData {
+				socket_path: self.cache.path().join(commit).join("server.sock"),
+				client_counter,
+				pid: None,
+				started_at: Instant::now(),
+				stopper: new_barrier().1,
+			}));
+		}
+
+		self.state.lock().unwrap().insert(
+			VersionKey {
+				quality: Quality::Stable,
+				commit: commit.to_string(),
+				user: None,
+			},
+			VersionState {
+				downloaded: false,
+				last_used: Instant::now(),
+				socket_path,
+			},
+		);
 	}
 }
 
+/// Asks the server to exit with SIGTERM, and kills its process tree if it
+/// hasn't exited within `SERVER_TERMINATE_TIMEOUT_SECS`.
+async fn terminate_server(log: &log::Logger, child: &mut Child, commit_prefix: &str) {
+	let pid = match child.id() {
+		Some(p) => p,
+		None => return, // already exited
+	};
+	debug!(log, "[{} process]: terminating {}", commit_prefix, pid);
+
+	#[cfg(unix)]
+	{
+		unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+		let timeout = Duration::from_secs(SERVER_TERMINATE_TIMEOUT_SECS);
+		if tokio::time::timeout(timeout, child.wait()).await.is_ok() {
+			return;
+		}
+
+		warning!(
+			log,
+			"[{} process]: did not exit {}s after SIGTERM, killing",
+			commit_prefix,
+			SERVER_TERMINATE_TIMEOUT_SECS
+		);
+	}
+
+	kill_tree(pid).await.ok();
+	let _ = child.kill().await;
+}
+
+/// Summary of a server version known to the `ConnectionManager`.
+struct VersionSummary {
+	quality: Quality,
+	commit: String,
+	user: Option<String>,
+	downloaded: bool,
+	/// Set once the server has started.
+	started: Option<StartData>,
+}
+
 struct StartArgs {
 	log: log::Logger,
 	args: ServeWebArgs,
 	release: Release,
+	/// User the server is for, when running with `--users-root`.
+	user: Option<String>,
 	opener: BarrierOpener<Result<StartData, String>>,
+	/// Opened when serve-web is shutting down.
+	shutdown: Barrier<()>,
+	processes: Arc<ServerProcesses>,
 }
 
 fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::Result<String> {
@@ -818,3 +1976,64 @@ fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::
 	f.write_all(prefer_token.as_bytes())?;
 	Ok(prefer_token)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_get_release_from_path() {
+		let commit = "0123456789abcdef0123456789abcdef01234567";
+		let (release, remaining) = get_release_from_path(
+			&format!("/insider-{}/static/main.js", commit),
+			Platform::LinuxX64,
+		)
+		.unwrap();
+		assert_eq!(release.quality, Quality::Insiders);
+		assert_eq!(release.commit, commit);
+		assert_eq!(remaining, "/static/main.js");
+
+		assert!(get_release_from_path("/stable-abc/", Platform::LinuxX64).is_none());
+		assert!(get_release_from_path("/static/main.js", Platform::LinuxX64).is_none());
+	}
+
+	#[test]
+	fn test_base_path() {
+		assert_eq!(normalize_base_path(None), "");
+		assert_eq!(normalize_base_path(Some("/")), "");
+		assert_eq!(normalize_base_path(Some("code")), "/code");
+		assert_eq!(normalize_base_path(Some("/code/")), "/code");
+		assert_eq!(normalize_base_path(Some("/tools/code")), "/tools/code");
+
+		assert_eq!(strip_base_path("", "/"), Some("/"));
+		assert_eq!(strip_base_path("", "/stable-abc/"), Some("/stable-abc/"));
+		assert_eq!(strip_base_path("/code", "/code"), Some("/"));
+		assert_eq!(
+			strip_base_path("/code", "/code/stable-abc/"),
+			Some("/stable-abc/")
+		);
+		assert_eq!(strip_base_path("/code", "/codes/"), None);
+		assert_eq!(strip_base_path("/code", "/stable-abc/"), None);
+	}
+
+	#[test]
+	fn test_parse_listen_address() {
+		assert_eq!(
+			"tcp://127.0.0.1:8000".parse::<ListenAddress>().unwrap(),
+			ListenAddress::Tcp("127.0.0.1:8000".parse().unwrap())
+		);
+		assert_eq!(
+			"tcp://[::1]:8000".parse::<ListenAddress>().unwrap(),
+			ListenAddress::Tcp("[::1]:8000".parse().unwrap())
+		);
+		assert_eq!(
+			"unix:///run/code.sock".parse::<ListenAddress>().unwrap(),
+			ListenAddress::Socket(PathBuf::from("/run/code.sock"))
+		);
+
+		assert!("tcp://localhost:8000".parse::<ListenAddress>().is_err());
+		assert!("tcp://127.0.0.1".parse::<ListenAddress>().is_err());
+		assert!("unix://".parse::<ListenAddress>().is_err());
+		assert!("127.0.0.1:8000".parse::<ListenAddress>().is_err());
+	}
+}
@@ -0,0 +1,240 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Response, StatusCode};
+use serde::Serialize;
+
+use crate::commands::args::{AccessLogFormat, ServeWebArgs};
+use crate::util::errors::CodeError;
+
+/// Number of rotated access logs that are kept, as `<path>.1` to `<path>.N`.
+const ROTATED_FILES: usize = 5;
+
+/// Response extension with the commit of the server that handled the request.
+#[derive(Clone)]
+pub struct ServedCommit(pub String);
+
+/// Response extension with the user that made the request.
+#[derive(Clone)]
+pub struct RequestUser(pub String);
+
+/// Details of the request, collected before it's handled.
+pub struct RequestInfo {
+	pub client: Option<SocketAddr>,
+	pub method: Method,
+	/// Path of the request. The query isn't logged since it may contain the
+	/// connection token.
+	pub path: String,
+	pub version: hyper::Version,
+	pub started_at: Instant,
+	pub time: DateTime<Local>,
+}
+
+/// Details of the response that are logged.
+struct ResponseInfo {
+	status: u16,
+	commit: Option<String>,
+	user: Option<String>,
+}
+
+impl ResponseInfo {
+	fn new(res: &Response<Body>) -> Self {
+		Self {
+			status: res.status().as_u16(),
+			commit: res.extensions().get::<ServedCommit>().map(|c| c.0.clone()),
+			user: res.extensions().get::<RequestUser>().map(|u| u.0.clone()),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+	time: String,
+	client: Option<String>,
+	user: Option<&'a str>,
+	method: &'a str,
+	path: &'a str,
+	status: u16,
+	bytes: u64,
+	duration_ms: u128,
+	commit: Option<&'a str>,
+}
+
+struct LogFile {
+	file: File,
+	size: u64,
+}
+
+/// Access log of requests handled by the web server, rotated by size.
+pub struct AccessLog {
+	format: AccessLogFormat,
+	path: PathBuf,
+	max_bytes: u64,
+	file: Mutex<LogFile>,
+}
+
+impl AccessLog {
+	/// Opens the access log if one was requested in the args.
+	pub fn from_args(args: &ServeWebArgs) -> Result<Option<Arc<Self>>, CodeError> {
+		let path = match &args.access_log {
+			Some(p) => PathBuf::from(p),
+			None => return Ok(None),
+		};
+
+		let file = open_append(&path).map_err(CodeError::CouldNotOpenAccessLog)?;
+		Ok(Some(Arc::new(Self {
+			format: args.access_log_format,
+			max_bytes: args.access_log_max_size * 1024 * 1024,
+			path,
+			file: Mutex::new(file),
+		})))
+	}
+
+	/// Logs the response once its body has been sent, so that the number of
+	/// bytes and duration include the body.
+	pub fn record(self: &Arc<Self>, info: RequestInfo, res: Response<Body>) -> Response<Body> {
+		// upgraded connections are logged once the upgrade is accepted
+		let res_info = ResponseInfo::new(&res);
+		if res.status() == StatusCode::SWITCHING_PROTOCOLS || res.body().is_end_stream() {
+			self.write(&info, &res_info, 0);
+			return res;
+		}
+
+		let (parts, mut body) = res.into_parts();
+		let (mut tx, rx) = Body::channel();
+		let log = self.clone();
+		tokio::spawn(async move {
+			let mut bytes = 0;
+			while let Some(chunk) = body.data().await {
+				let chunk = match chunk {
+					Ok(c) => c,
+					Err(_) => {
+						tx.abort();
+						break;
+					}
+				};
+
+				let len = chunk.len() as u64;
+				if tx.send_data(chunk).await.is_err() {
+					break;
+				}
+				bytes += len;
+			}
+
+			log.write(&info, &res_info, bytes);
+		});
+
+		Response::from_parts(parts, rx)
+	}
+
+	fn write(&self, info: &RequestInfo, res: &ResponseInfo, bytes: u64) {
+		let commit = res.commit.as_deref();
+		let user = res.user.as_deref();
+		let duration = info.started_at.elapsed();
+		let line = match self.format {
+			AccessLogFormat::Clf => format!(
+				"{} - {} [{}] \"{} {} {:?}\" {} {} {} {}\n",
+				info.client
+					.map(|c| c.ip().to_string())
+					.unwrap_or_else(|| "-".to_string()),
+				user.unwrap_or("-"),
+				info.time.format("%d/%b/%Y:%H:%M:%S %z"),
+				info.method,
+				info.path,
+				info.version,
+				res.status,
+				bytes,
+				duration.as_millis(),
+				commit.unwrap_or("-"),
+			),
+			AccessLogFormat::Json => {
+				let mut line = serde_json::to_string(&Entry {
+					time: info.time.to_rfc3339(),
+					client: info.client.map(|c| c.to_string()),
+					user,
+					method: info.method.as_str(),
+					path: &info.path,
+					status: res.status,
+					bytes,
+					duration_ms: duration.as_millis(),
+					commit,
+				})
+				.unwrap();
+				line.push('\n');
+				line
+			}
+		};
+
+		// ignore any errors, not much we can do if logging fails...
+		let mut file = self.file.lock().unwrap();
+		if file.size + line.len() as u64 > self.max_bytes {
+			if let Ok(f) = rotate(&self.path) {
+				*file = f;
+			}
+		}
+		if file.file.write_all(line.as_bytes()).is_ok() {
+			file.size += line.len() as u64;
+		}
+	}
+}
+
+fn open_append(path: &Path) -> std::io::Result<LogFile> {
+	let file = fs::OpenOptions::new()
+		.append(true)
+		.create(true)
+		.open(path)?;
+	let size = file.metadata()?.len();
+	Ok(LogFile { file, size })
+}
+
+/// Moves `<path>` to `<path>.1`, `<path>.1` to `<path>.2`, and so on, and
+/// opens a new log at the path.
+fn rotate(path: &Path) -> std::io::Result<LogFile> {
+	let rotated = |i: usize| {
+		let mut p = path.as_os_str().to_owned();
+		p.push(format!(".{}", i));
+		PathBuf::from(p)
+	};
+
+	for i in (1..ROTATED_FILES).rev() {
+		let _ = fs::rename(rotated(i), rotated(i + 1));
+	}
+	fs::rename(path, rotated(1))?;
+	open_append(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rotate() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("access.log");
+		for i in 0..=ROTATED_FILES + 1 {
+			fs::write(&path, i.to_string()).unwrap();
+			let log = rotate(&path).unwrap();
+			assert_eq!(log.size, 0);
+		}
+
+		let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+		assert_eq!(read("access.log"), "");
+		assert_eq!(read("access.log.1"), (ROTATED_FILES + 1).to_string());
+		assert_eq!(read(&format!("access.log.{}", ROTATED_FILES)), "2");
+		assert!(!dir
+			.path()
+			.join(format!("access.log.{}", ROTATED_FILES + 1))
+			.exists());
+	}
+}
@@ -0,0 +1,475 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+
+use crate::async_pipe::get_socket_rw_stream;
+use crate::commands::args::{ServeWebArgs, ServeWebSubcommand};
+use crate::commands::CommandContext;
+use crate::options::Quality;
+use crate::update_service::{Release, TargetKind};
+use crate::util::errors::{wrap, AnyError, CodeError, StatusError};
+
+use super::auth::{constant_time_eq, AuthAttempt};
+use super::{
+	is_commit_hash, listen_addresses, normalize_base_path, ConnectionManager, ListenAddress,
+	CONNECTION_TOKEN_FILE_NAME,
+};
+
+/// Prefix of the admin API paths. Requests must have the connection token as
+/// a bearer token in their `Authorization` header.
+pub const ADMIN_PATH_PREFIX: &str = "/_vscode-cli/admin/";
+
+const METHOD_VERSIONS: &str = "versions";
+const METHOD_STOP: &str = "stop";
+const METHOD_DOWNLOAD: &str = "download";
+const METHOD_EVICT: &str = "evict";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionStatus {
+	Downloading,
+	Starting,
+	Running,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionInfo {
+	pub quality: Quality,
+	pub commit: String,
+	/// User the server is for, when running with `--users-root`.
+	pub user: Option<String>,
+	pub status: VersionStatus,
+	pub pid: Option<u32>,
+	pub uptime_secs: Option<u64>,
+	pub clients: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionRequest {
+	#[serde(default)]
+	pub quality: Option<Quality>,
+	pub commit: String,
+}
+
+/// Handles a request to the `method` under the `ADMIN_PATH_PREFIX`.
+pub async fn handle(cm: &ConnectionManager, method: &str, req: Request<Body>) -> Response<Body> {
+	if let Some(res) = authorize(cm, &req, "the admin API") {
+		return res;
+	}
+
+	let mut res = handle_method(cm, method, req).await;
+	res.extensions_mut().insert(AuthAttempt::Succeeded);
+	res
+}
+
+/// Checks that the request has the connection token as a bearer token,
+/// returning the response to send instead if it doesn't. `feature` names what's
+/// being accessed in the error returned when there's no connection token.
+pub fn authorize(
+	cm: &ConnectionManager,
+	req: &Request<Body>,
+	feature: &str,
+) -> Option<Response<Body>> {
+	let token = match &cm.args.connection_token {
+		Some(t) => t,
+		None => {
+			return Some(text_response(
+				StatusCode::FORBIDDEN,
+				&format!("{} is not available without a connection token", feature),
+			))
+		}
+	};
+
+	if !is_authorized(req, token) {
+		let mut res = text_response(StatusCode::UNAUTHORIZED, "invalid or missing bearer token");
+		res.extensions_mut().insert(AuthAttempt::Failed);
+		return Some(res);
+	}
+
+	None
+}
+
+async fn handle_method(cm: &ConnectionManager, method: &str, req: Request<Body>) -> Response<Body> {
+	match (req.method(), method) {
+		(&Method::GET, METHOD_VERSIONS) => json_response(&list_versions(cm)),
+		(&Method::POST, METHOD_STOP) => match read_version_request(req).await {
+			Ok(r) if cm.stop_version(r.quality, &r.commit) => json_response(&()),
+			Ok(r) => text_response(
+				StatusCode::NOT_FOUND,
+				&format!("server {} is not running", r.commit),
+			),
+			Err(e) => e,
+		},
+		(&Method::POST, METHOD_DOWNLOAD) => {
+			let r = match read_version_request(req).await {
+				Ok(r) => r,
+				Err(e) => return e,
+			};
+			let quality = match r.quality.map(Ok).unwrap_or_else(|| cm.get_quality()) {
+				Ok(q) => q,
+				Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+			};
+			let release = Release {
+				name: "".to_string(),
+				commit: r.commit,
+				platform: cm.platform,
+				target: TargetKind::Web,
+				quality,
+			};
+			match cm.download_to_cache(release).await {
+				Ok(_) => json_response(&()),
+				Err(CodeError::ServerNotYetDownloaded) => text_response(
+					StatusCode::CONFLICT,
+					"the server is already being downloaded",
+				),
+				Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+			}
+		}
+		(&Method::POST, METHOD_EVICT) => match read_version_request(req).await {
+			Ok(r) => match cm.evict_version(&r.commit) {
+				Ok(_) => json_response(&()),
+				Err(e @ CodeError::ServerVersionInUse(_)) => {
+					text_response(StatusCode::CONFLICT, &e.to_string())
+				}
+				Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+			},
+			Err(e) => e,
+		},
+		_ => text_response(StatusCode::NOT_FOUND, "unknown admin method"),
+	}
+}
+
+fn list_versions(cm: &ConnectionManager) -> Vec<VersionInfo> {
+	cm.list_versions()
+		.into_iter()
+		.map(|v| VersionInfo {
+			quality: v.quality,
+			commit: v.commit,
+			user: v.user,
+			status: match (&v.started, v.downloaded) {
+				(Some(_), _) => VersionStatus::Running,
+				(None, true) => VersionStatus::Starting,
+				(None, false) => VersionStatus::Downloading,
+			},
+			pid: v.started.as_ref().and_then(|d| d.pid),
+			uptime_secs: v.started.as_ref().map(|d| d.started_at.elapsed().as_secs()),
+			clients: v
+				.started
+				.map(|d| *d.client_counter.borrow())
+				.unwrap_or_default(),
+		})
+		.collect()
+}
+
+/// Checks the bearer token in constant time.
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+	let given = req
+		.headers()
+		.get(hyper::header::AUTHORIZATION)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.strip_prefix("Bearer "))
+		.unwrap_or_default();
+
+	constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+async fn read_version_request(req: Request<Body>) -> Result<VersionRequest, Response<Body>> {
+	let body = hyper::body::to_bytes(req.into_body())
+		.await
+		.map_err(|e| text_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+	let r: VersionRequest = serde_json::from_slice(&body)
+		.map_err(|e| text_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+	if !is_commit_hash(&r.commit) {
+		return Err(text_response(
+			StatusCode::BAD_REQUEST,
+			&CodeError::InvalidCommitHash(r.commit).to_string(),
+		));
+	}
+
+	Ok(r)
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+	Response::builder()
+		.status(200)
+		.header("Content-Type", "application/json")
+		.body(Body::from(serde_json::to_vec(value).unwrap()))
+		.unwrap()
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.body(Body::from(message.to_string()))
+		.unwrap()
+}
+
+/// Runs the admin subcommand against the `serve-web` instance that the
+/// first listen address in the args points to.
+pub async fn run_command(
+	ctx: CommandContext,
+	args: ServeWebArgs,
+	command: ServeWebSubcommand,
+) -> Result<i32, AnyError> {
+	let (method, path, body) = match &command {
+		ServeWebSubcommand::HashPassword => unreachable!("not an admin command"),
+		ServeWebSubcommand::List => (Method::GET, METHOD_VERSIONS, None),
+		ServeWebSubcommand::Stop(a) => (Method::POST, METHOD_STOP, Some(a)),
+		ServeWebSubcommand::Download(a) => (Method::POST, METHOD_DOWNLOAD, Some(a)),
+		ServeWebSubcommand::Evict(a) => (Method::POST, METHOD_EVICT, Some(a)),
+	};
+
+	let body = match body {
+		Some(a) => Body::from(
+			serde_json::to_vec(&VersionRequest {
+				quality: args.quality,
+				commit: a.commit.clone(),
+			})
+			.unwrap(),
+		),
+		None => Body::empty(),
+	};
+
+	let token = read_connection_token(&ctx, &args)?;
+	let req = Request::builder()
+		.method(method)
+		.uri(format!(
+			"{}{}{}",
+			normalize_base_path(args.server_base_path.as_deref()),
+			ADMIN_PATH_PREFIX,
+			path
+		))
+		.header(hyper::header::HOST, "localhost")
+		.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token))
+		.header(hyper::header::CONTENT_TYPE, "application/json")
+		.body(body)
+		.unwrap();
+
+	let res = send_request(&args, req).await?;
+	let status = res.status();
+	let body = hyper::body::to_bytes(res.into_body())
+		.await
+		.map_err(|e| wrap(e, "error reading response from the web server"))?;
+	if !status.is_success() {
+		return Err(StatusError {
+			body: String::from_utf8_lossy(&body).to_string(),
+			status_code: status.as_u16(),
+			url: path.to_string(),
+		}
+		.into());
+	}
+
+	match command {
+		ServeWebSubcommand::HashPassword => unreachable!("not an admin command"),
+		ServeWebSubcommand::List => ctx.log.result(String::from_utf8_lossy(&body)),
+		ServeWebSubcommand::Stop(a) => ctx.log.result(format!("Stopped server {}", a.commit)),
+		ServeWebSubcommand::Download(a) => {
+			ctx.log.result(format!("Downloaded server {}", a.commit))
+		}
+		ServeWebSubcommand::Evict(a) => ctx.log.result(format!("Removed server {}", a.commit)),
+	}
+
+	Ok(0)
+}
+
+fn read_connection_token(ctx: &CommandContext, args: &ServeWebArgs) -> Result<String, AnyError> {
+	if let Some(t) = &args.connection_token {
+		return Ok(t.clone());
+	}
+
+	let path = match &args.connection_token_file {
+		Some(f) => Path::new(f).to_owned(),
+		None => ctx.paths.root().join(CONNECTION_TOKEN_FILE_NAME),
+	};
+
+	let token = std::fs::read_to_string(&path).map_err(|e| {
+		wrap(
+			e,
+			format!("error reading connection token from {}", path.display()),
+		)
+	})?;
+	Ok(token.trim().to_string())
+}
+
+async fn send_request(args: &ServeWebArgs, req: Request<Body>) -> Result<Response<Body>, AnyError> {
+	let addr = match listen_addresses(args)?.into_iter().next() {
+		Some(ListenAddress::Tcp(addr)) => addr,
+		Some(ListenAddress::Socket(s)) => {
+			let rw = get_socket_rw_stream(&s).await?;
+			return send_request_on(rw, req).await;
+		}
+		None => unreachable!("there is always at least one listen address"),
+	};
+
+	let tcp = TcpStream::connect(connect_address(addr))
+		.await
+		.map_err(|e| wrap(e, format!("could not connect to {}", addr)))?;
+
+	if args.tls_cert.is_none() && !args.tls_self_signed {
+		return send_request_on(tcp, req).await;
+	}
+
+	let connector = native_tls::TlsConnector::builder()
+		.danger_accept_invalid_certs(args.tls_self_signed)
+		.build()
+		.map_err(|e| wrap(e, "error creating TLS connector"))?;
+	let tls = tokio_native_tls::TlsConnector::from(connector)
+		.connect(&tls_server_name(args, &addr), tcp)
+		.await
+		.map_err(|e| wrap(e, "error establishing TLS connection"))?;
+	send_request_on(tls, req).await
+}
+
+/// Gets the address to connect to for a listen address. Servers listening on
+/// all addresses are connected to on localhost.
+fn connect_address(addr: SocketAddr) -> SocketAddr {
+	match addr.ip() {
+		IpAddr::V4(ip) if ip.is_unspecified() => {
+			SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+		}
+		IpAddr::V6(ip) if ip.is_unspecified() => {
+			SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+		}
+		_ => addr,
+	}
+}
+
+/// Gets the name to check the server's certificate against, see `--tls-server-name`.
+fn tls_server_name(args: &ServeWebArgs, addr: &SocketAddr) -> String {
+	if let Some(name) = &args.tls_server_name {
+		return name.clone();
+	}
+
+	let ip = connect_address(*addr).ip();
+	if ip.is_loopback() {
+		"localhost".to_string()
+	} else {
+		ip.to_string()
+	}
+}
+
+async fn send_request_on<S>(rw: S, req: Request<Body>) -> Result<Response<Body>, AnyError>
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let (mut sender, connection) = hyper::client::conn::Builder::new()
+		.handshake(rw)
+		.await
+		.map_err(|e| wrap(e, "error connecting to the web server"))?;
+	tokio::spawn(connection);
+
+	sender
+		.send_request(req)
+		.await
+		.map_err(|e| wrap(e, "error sending request to the web server").into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::commands::serve_web::test_args;
+
+	const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+	fn request(
+		method: Method,
+		token: Option<&str>,
+		body: Option<&VersionRequest>,
+	) -> Request<Body> {
+		let mut req = Request::builder().method(method);
+		if let Some(t) = token {
+			req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {}", t));
+		}
+		let body = body
+			.map(|b| Body::from(serde_json::to_vec(b).unwrap()))
+			.unwrap_or_default();
+		req.body(body).unwrap()
+	}
+
+	fn version_request() -> VersionRequest {
+		VersionRequest {
+			quality: None,
+			commit: COMMIT.to_string(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_requires_connection_token() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--without-connection-token"]);
+		let res = handle(&cm, METHOD_VERSIONS, request(Method::GET, Some("x"), None)).await;
+		assert_eq!(res.status(), StatusCode::FORBIDDEN);
+		assert_eq!(res.extensions().get::<AuthAttempt>(), None);
+	}
+
+	#[tokio::test]
+	async fn test_requires_bearer_token() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--connection-token", "secret"]);
+
+		for token in [None, Some("wrong")] {
+			let res = handle(&cm, METHOD_VERSIONS, request(Method::GET, token, None)).await;
+			assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+			assert_eq!(res.extensions().get(), Some(&AuthAttempt::Failed));
+		}
+
+		let res = handle(
+			&cm,
+			METHOD_VERSIONS,
+			request(Method::GET, Some("secret"), None),
+		)
+		.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(res.extensions().get(), Some(&AuthAttempt::Succeeded));
+	}
+
+	#[tokio::test]
+	async fn test_evict_in_use() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--connection-token", "secret"]);
+		cm.insert_test_download(COMMIT);
+		cm.insert_test_version(COMMIT, None);
+
+		let req = request(Method::POST, Some("secret"), Some(&version_request()));
+		let res = handle(&cm, METHOD_EVICT, req).await;
+		assert_eq!(res.status(), StatusCode::CONFLICT);
+		assert!(cm.cache.exists(COMMIT).is_some());
+	}
+
+	#[tokio::test]
+	async fn test_stop_not_running() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--connection-token", "secret"]);
+
+		let req = request(Method::POST, Some("secret"), Some(&version_request()));
+		let res = handle(&cm, METHOD_STOP, req).await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+		// servers that are still downloading aren't running either
+		cm.insert_test_version(COMMIT, None);
+		let req = request(Method::POST, Some("secret"), Some(&version_request()));
+		let res = handle(&cm, METHOD_STOP, req).await;
+		assert_eq!(res.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn test_tls_server_name() {
+		let mut args = test_args(&[]);
+		let name = |args: &ServeWebArgs, addr: &str| tls_server_name(args, &addr.parse().unwrap());
+
+		assert_eq!(name(&args, "127.0.0.1:8000"), "localhost");
+		assert_eq!(name(&args, "0.0.0.0:8000"), "localhost");
+		assert_eq!(name(&args, "[::]:8000"), "localhost");
+		assert_eq!(name(&args, "192.0.2.1:8000"), "192.0.2.1");
+		assert_eq!(name(&args, "[2001:db8::1]:8000"), "2001:db8::1");
+
+		args.tls_server_name = Some("code.example.com".to_string());
+		assert_eq!(name(&args, "0.0.0.0:8000"), "code.example.com");
+	}
+}
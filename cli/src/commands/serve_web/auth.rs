@@ -0,0 +1,544 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose, Engine as _};
+use const_format::concatcp;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response};
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+
+use crate::commands::args::ServeWebArgs;
+use crate::constants::QUALITYLESS_SERVER_NAME;
+use crate::util::errors::CodeError;
+
+use super::users::is_valid_user_name;
+use super::{cookie_path, extract_cookie, normalize_base_path};
+
+/// Path of the login page.
+pub const LOGIN_PATH: &str = "/_vscode-cli/login";
+/// Path that ends the current session.
+pub const LOGOUT_PATH: &str = "/_vscode-cli/logout";
+/// HTTP-only cookie where the session ID is stored.
+const SESSION_COOKIE_NAME: &str = "vscode-cli-session";
+/// Number of random bytes in session IDs.
+const SESSION_ID_BYTES: usize = 32;
+/// Largest login form that's read, which is plenty for a user name and password.
+const MAX_LOGIN_BODY_BYTES: usize = 4096;
+/// Number of passwords that can be verified at once. Verification is slow on
+/// purpose, so this keeps login attempts from using up the blocking threads.
+const MAX_CONCURRENT_VERIFICATIONS: usize = 4;
+
+/// Identifier of the hashing scheme, used as the prefix of stored hashes.
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2 iterations for new hashes, following the OWASP recommendation.
+const HASH_ITERATIONS: u32 = 600_000;
+const HASH_SALT_BYTES: usize = 16;
+const HASH_OUTPUT_BYTES: usize = 32;
+
+/// A salted password hash, stored as `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+/// with the salt and hash in base64.
+#[derive(Clone)]
+pub struct PasswordHash {
+	iterations: u32,
+	salt: Vec<u8>,
+	hash: Vec<u8>,
+}
+
+impl PasswordHash {
+	/// Hashes the password with a new random salt.
+	pub fn new(password: &str) -> Self {
+		Self::with_iterations(password, HASH_ITERATIONS)
+	}
+
+	fn with_iterations(password: &str, iterations: u32) -> Self {
+		let salt: [u8; HASH_SALT_BYTES] = rand::random();
+		let hash = derive(password, &salt, iterations);
+		Self {
+			iterations,
+			salt: salt.to_vec(),
+			hash,
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<Self, CodeError> {
+		let invalid = || CodeError::InvalidPasswordHash(HASH_SCHEME);
+		let mut parts = s.trim().split('$');
+		if parts.next() != Some(HASH_SCHEME) {
+			return Err(invalid());
+		}
+
+		let iterations = parts
+			.next()
+			.and_then(|i| i.parse().ok())
+			.filter(|i| *i > 0)
+			.ok_or_else(invalid)?;
+		let mut decode = || {
+			parts
+				.next()
+				.and_then(|p| general_purpose::STANDARD.decode(p).ok())
+				.filter(|p| !p.is_empty())
+				.ok_or_else(invalid)
+		};
+		let salt = decode()?;
+		let hash = decode()?;
+		if parts.next().is_some() {
+			return Err(invalid());
+		}
+
+		Ok(Self {
+			iterations,
+			salt,
+			hash,
+		})
+	}
+
+	/// Checks the password against the hash, in constant time.
+	pub fn verify(&self, password: &str) -> bool {
+		let hash = derive(password, &self.salt, self.iterations);
+		constant_time_eq(&hash, &self.hash)
+	}
+}
+
+impl fmt::Display for PasswordHash {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}${}${}${}",
+			HASH_SCHEME,
+			self.iterations,
+			general_purpose::STANDARD.encode(&self.salt),
+			general_purpose::STANDARD.encode(&self.hash)
+		)
+	}
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+	let mut out = [0u8; HASH_OUTPUT_BYTES];
+	pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut out);
+	out.to_vec()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Parses an accounts file of `user:hash` lines. Empty lines and lines
+/// starting with `#` are ignored.
+pub fn parse_accounts(contents: &str) -> Result<HashMap<String, PasswordHash>, CodeError> {
+	let mut accounts = HashMap::new();
+	for (i, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let invalid =
+			|reason: &str| CodeError::InvalidAccountsFile(format!("line {}: {}", i + 1, reason));
+		let (user, hash) = line
+			.split_once(':')
+			.ok_or_else(|| invalid("expected user:hash"))?;
+		if !is_valid_user_name(user) {
+			return Err(invalid("invalid user name"));
+		}
+		let hash = PasswordHash::parse(hash).map_err(|e| invalid(&e.to_string()))?;
+		if accounts.insert(user.to_string(), hash).is_some() {
+			return Err(invalid("duplicate user"));
+		}
+	}
+
+	if accounts.is_empty() {
+		return Err(CodeError::InvalidAccountsFile(
+			"no accounts are defined".to_string(),
+		));
+	}
+
+	Ok(accounts)
+}
+
+/// Response extension recording the outcome of checking the client's
+/// credentials, used to lock out clients that fail too often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthAttempt {
+	Failed,
+	Succeeded,
+}
+
+/// Credentials that users log in with.
+enum Credentials {
+	/// A single password, shared by everyone.
+	Password(PasswordHash),
+	/// Named accounts, each with their own password.
+	Accounts(HashMap<String, PasswordHash>),
+}
+
+/// A logged-in session.
+#[derive(Clone)]
+pub struct Session {
+	/// The account that logged in, when using accounts.
+	pub user: Option<String>,
+	expires_at: Instant,
+}
+
+/// Password login for the web UI, keeping track of sessions in memory.
+pub struct Authenticator {
+	credentials: Credentials,
+	session_timeout: Duration,
+	/// Whether cookies should be marked as `Secure`, when serving over TLS.
+	secure: bool,
+	/// Normalized `--server-base-path` that paths and cookies are under
+	base_path: String,
+	/// Mapping of session IDs to the session
+	sessions: Mutex<HashMap<String, Session>>,
+	/// Permits for password verifications, see `MAX_CONCURRENT_VERIFICATIONS`
+	verifications: Semaphore,
+}
+
+impl Authenticator {
+	/// Creates the authenticator if a password hash or accounts file was given in the args.
+	pub fn from_args(args: &ServeWebArgs) -> Result<Option<Self>, CodeError> {
+		let credentials = match (
+			&args.password_hash,
+			&args.password_hash_file,
+			&args.accounts_file,
+		) {
+			(Some(h), _, _) => Credentials::Password(PasswordHash::parse(h)?),
+			(None, Some(f), _) => Credentials::Password(PasswordHash::parse(
+				&std::fs::read_to_string(f).map_err(CodeError::CouldNotReadPasswordHash)?,
+			)?),
+			(None, None, Some(f)) => {
+				Credentials::Accounts(parse_accounts(&std::fs::read_to_string(f).map_err(
+					|e| CodeError::InvalidAccountsFile(format!("could not read {}: {}", f, e)),
+				)?)?)
+			}
+			(None, None, None) => return Ok(None),
+		};
+
+		Ok(Some(Self {
+			credentials,
+			session_timeout: Duration::from_secs(args.session_timeout),
+			secure: args.tls_cert.is_some() || args.tls_self_signed,
+			base_path: normalize_base_path(args.server_base_path.as_deref()),
+			sessions: Mutex::default(),
+			verifications: Semaphore::new(MAX_CONCURRENT_VERIFICATIONS),
+		}))
+	}
+
+	/// Gets the request's session, if it's valid and unexpired.
+	pub fn get_session(&self, req: &Request<Body>) -> Option<Session> {
+		let id = extract_cookie(req, SESSION_COOKIE_NAME)?;
+
+		let mut sessions = self.sessions.lock().unwrap();
+		let now = Instant::now();
+		sessions.retain(|_, s| s.expires_at > now);
+		sessions.get(&id).cloned()
+	}
+
+	/// Handles a request to the `LOGIN_PATH`, serving the login form on GET
+	/// and starting a session on a POST with the correct credentials.
+	pub async fn handle_login(&self, req: Request<Body>) -> Response<Body> {
+		let with_user = matches!(self.credentials, Credentials::Accounts(_));
+		if req.method() != Method::POST {
+			return response::login_page(&self.base_path, false, with_user);
+		}
+
+		let body = match read_login_body(req).await {
+			Ok(Some(b)) => b,
+			Ok(None) => return response::too_large(),
+			Err(_) => return response::login_page(&self.base_path, true, with_user),
+		};
+		let field = |name: &str| {
+			url::form_urlencoded::parse(&body)
+				.find(|(k, _)| k == name)
+				.map(|(_, v)| v.to_string())
+				.unwrap_or_default()
+		};
+		let password = field("password");
+
+		let (user, hash) = match &self.credentials {
+			Credentials::Password(h) => (None, h.clone()),
+			Credentials::Accounts(accounts) => {
+				let user = field("user");
+				match accounts.get(&user) {
+					Some(h) => (Some(user), h.clone()),
+					// still verify against some hash so that unknown users
+					// can't be told apart by the response time
+					None => (None, accounts.values().next().unwrap().clone()),
+				}
+			}
+		};
+		let is_known_user = user.is_some() || !with_user;
+
+		// hashing is intentionally slow, so keep it off the async runtime
+		let valid = match self.verifications.acquire().await {
+			Ok(_permit) => tokio::task::spawn_blocking(move || hash.verify(&password))
+				.await
+				.unwrap_or(false),
+			Err(_) => false,
+		};
+		if !valid || !is_known_user {
+			let mut res = response::login_page(&self.base_path, true, with_user);
+			res.extensions_mut().insert(AuthAttempt::Failed);
+			return res;
+		}
+
+		let id = general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; SESSION_ID_BYTES]>());
+		self.sessions.lock().unwrap().insert(
+			id.clone(),
+			Session {
+				user,
+				expires_at: Instant::now() + self.session_timeout,
+			},
+		);
+
+		let mut res = response::redirect(
+			&format!("{}/", self.base_path),
+			Some(self.session_cookie(&id, self.session_timeout.as_secs())),
+		);
+		res.extensions_mut().insert(AuthAttempt::Succeeded);
+		res
+	}
+
+	/// Handles a request to the `LOGOUT_PATH`, ending the session.
+	pub fn handle_logout(&self, req: Request<Body>) -> Response<Body> {
+		if let Some(id) = extract_cookie(&req, SESSION_COOKIE_NAME) {
+			self.sessions.lock().unwrap().remove(&id);
+		}
+
+		response::redirect(
+			&format!("{}{}", self.base_path, LOGIN_PATH),
+			Some(self.session_cookie("", 0)),
+		)
+	}
+
+	/// Response for a request without a valid session. Page loads are
+	/// redirected to the login page, while other requests are rejected.
+	pub fn login_required(&self, req: &Request<Body>) -> Response<Body> {
+		let accepts_html = req
+			.headers()
+			.get(hyper::header::ACCEPT)
+			.and_then(|h| h.to_str().ok())
+			.map(|h| h.contains("text/html"))
+			.unwrap_or(false);
+
+		if req.method() == Method::GET && accepts_html {
+			response::redirect(&format!("{}{}", self.base_path, LOGIN_PATH), None)
+		} else {
+			Response::builder()
+				.status(401)
+				.body(Body::from("Login required"))
+				.unwrap()
+		}
+	}
+
+	fn session_cookie(&self, value: &str, max_age: u64) -> String {
+		format!(
+			"{}={}; SameSite=Strict; HttpOnly; Max-Age={}; Path={}{}",
+			SESSION_COOKIE_NAME,
+			value,
+			max_age,
+			cookie_path(&self.base_path),
+			if self.secure { "; Secure" } else { "" }
+		)
+	}
+}
+
+/// Reads the body of a login request, or returns None if it's larger than
+/// `MAX_LOGIN_BODY_BYTES`.
+async fn read_login_body(req: Request<Body>) -> Result<Option<Vec<u8>>, hyper::Error> {
+	let declared_len = req
+		.headers()
+		.get(hyper::header::CONTENT_LENGTH)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.parse::<u64>().ok());
+	if declared_len.is_some_and(|l| l > MAX_LOGIN_BODY_BYTES as u64) {
+		return Ok(None);
+	}
+
+	// the length may be missing from chunked requests, so check as it's read
+	let mut body = req.into_body();
+	let mut out = Vec::new();
+	while let Some(chunk) = body.data().await {
+		let chunk = chunk?;
+		if out.len() + chunk.len() > MAX_LOGIN_BODY_BYTES {
+			return Ok(None);
+		}
+		out.extend_from_slice(&chunk);
+	}
+
+	Ok(Some(out))
+}
+
+mod response {
+	use super::*;
+
+	const LOGIN_PAGE_START: &str = concatcp!(
+		"<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>",
+		QUALITYLESS_SERVER_NAME,
+		"</title><style>body{font-family:sans-serif;display:flex;justify-content:center;margin-top:20vh}form{display:flex;flex-direction:column;gap:8px;width:280px}.error{color:#c00}</style></head><body><form method=\"POST\" action=\""
+	);
+	const LOGIN_PAGE_TITLE: &str = concatcp!("\"><h2>", QUALITYLESS_SERVER_NAME, "</h2>");
+	const LOGIN_PAGE_USER: &str = "<input type=\"text\" name=\"user\" placeholder=\"User name\" autocomplete=\"username\" autofocus required>";
+	const LOGIN_PAGE_PASSWORD: &str =
+		"<input type=\"password\" name=\"password\" placeholder=\"Password\" required>";
+	// only the first field is focused, which is the password when there's no user name
+	const LOGIN_PAGE_PASSWORD_FOCUSED: &str =
+		"<input type=\"password\" name=\"password\" placeholder=\"Password\" autofocus required>";
+	const LOGIN_PAGE_END: &str = "<button type=\"submit\">Log in</button></form></body></html>";
+
+	pub fn login_page(base_path: &str, failed: bool, with_user: bool) -> Response<Body> {
+		let error = match (failed, with_user) {
+			(true, true) => "<div class=\"error\">Incorrect user name or password</div>",
+			(true, false) => "<div class=\"error\">Incorrect password</div>",
+			(false, _) => "",
+		};
+		let (user, password) = if with_user {
+			(LOGIN_PAGE_USER, LOGIN_PAGE_PASSWORD)
+		} else {
+			("", LOGIN_PAGE_PASSWORD_FOCUSED)
+		};
+
+		Response::builder()
+			.status(if failed { 401 } else { 200 })
+			.header("Content-Type", "text/html")
+			.header("Cache-Control", "no-store")
+			.body(Body::from(format!(
+				"{}{}{}{}{}{}{}{}",
+				LOGIN_PAGE_START,
+				base_path,
+				LOGIN_PATH,
+				LOGIN_PAGE_TITLE,
+				error,
+				user,
+				password,
+				LOGIN_PAGE_END
+			)))
+			.unwrap()
+	}
+
+	pub fn too_large() -> Response<Body> {
+		Response::builder()
+			.status(413)
+			.body(Body::from("Login form is too large"))
+			.unwrap()
+	}
+
+	pub fn redirect(location: &str, cookie: Option<String>) -> Response<Body> {
+		let mut res = Response::builder()
+			.status(302)
+			.header("Location", location)
+			.header("Cache-Control", "no-store");
+		if let Some(c) = cookie {
+			res = res.header(hyper::header::SET_COOKIE, c);
+		}
+
+		res.body(Body::empty()).unwrap()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_password_hash_roundtrip() {
+		let hash = PasswordHash::with_iterations("hunter2", 1000);
+		let parsed = PasswordHash::parse(&hash.to_string()).unwrap();
+		assert!(parsed.verify("hunter2"));
+		assert!(!parsed.verify("hunter3"));
+		assert!(!parsed.verify(""));
+	}
+
+	#[test]
+	fn test_password_hash_parse_invalid() {
+		assert!(PasswordHash::parse("").is_err());
+		assert!(PasswordHash::parse("hunter2").is_err());
+		assert!(PasswordHash::parse("pbkdf2-sha256$0$AAAA$AAAA").is_err());
+		assert!(PasswordHash::parse("pbkdf2-sha256$1000$AAAA").is_err());
+		assert!(PasswordHash::parse("md5$1000$AAAA$AAAA").is_err());
+	}
+
+	#[test]
+	fn test_parse_accounts() {
+		let hash = PasswordHash::with_iterations("hunter2", 1000).to_string();
+		let accounts =
+			parse_accounts(&format!("# comment\n\nalice:{}\n  bob:{}  \n", hash, hash)).unwrap();
+		assert_eq!(accounts.len(), 2);
+		assert!(accounts["alice"].verify("hunter2"));
+		assert!(accounts["bob"].verify("hunter2"));
+
+		assert!(parse_accounts("").is_err());
+		assert!(parse_accounts("alice").is_err());
+		assert!(parse_accounts("alice:hunter2").is_err());
+		assert!(parse_accounts(&format!("../alice:{}", hash)).is_err());
+		assert!(parse_accounts(&format!("alice:{}\nalice:{}", hash, hash)).is_err());
+	}
+
+	fn test_authenticator() -> Authenticator {
+		Authenticator {
+			credentials: Credentials::Password(PasswordHash::with_iterations("hunter2", 1000)),
+			session_timeout: Duration::from_secs(60),
+			secure: false,
+			base_path: String::new(),
+			sessions: Mutex::default(),
+			verifications: Semaphore::new(MAX_CONCURRENT_VERIFICATIONS),
+		}
+	}
+
+	fn login_request(body: impl Into<Body>, content_length: Option<usize>) -> Request<Body> {
+		let mut req = Request::builder().method(Method::POST).uri(LOGIN_PATH);
+		if let Some(l) = content_length {
+			req = req.header(hyper::header::CONTENT_LENGTH, l);
+		}
+		req.body(body.into()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_login() {
+		let auth = test_authenticator();
+
+		let res = auth
+			.handle_login(login_request("password=hunter3", None))
+			.await;
+		assert_eq!(res.status(), 401);
+		assert_eq!(res.extensions().get(), Some(&AuthAttempt::Failed));
+
+		let res = auth
+			.handle_login(login_request("password=hunter2", None))
+			.await;
+		assert_eq!(res.status(), 302);
+		assert_eq!(res.extensions().get(), Some(&AuthAttempt::Succeeded));
+	}
+
+	#[tokio::test]
+	async fn test_login_body_too_large() {
+		let auth = test_authenticator();
+		let body = format!("password={}", "a".repeat(MAX_LOGIN_BODY_BYTES));
+
+		// rejected from the declared length, before the body is read
+		let res = auth
+			.handle_login(login_request(Body::empty(), Some(body.len())))
+			.await;
+		assert_eq!(res.status(), 413);
+
+		// rejected while reading when there's no declared length
+		let (mut sender, chunked) = Body::channel();
+		tokio::spawn(async move {
+			for c in body.into_bytes().chunks(1024) {
+				if sender.send_data(c.to_vec().into()).await.is_err() {
+					break;
+				}
+			}
+		});
+		let res = auth.handle_login(login_request(chunked, None)).await;
+		assert_eq!(res.status(), 413);
+		assert_eq!(res.extensions().get::<AuthAttempt>(), None);
+	}
+}
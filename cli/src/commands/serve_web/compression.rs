@@ -0,0 +1,412 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::{write::GzEncoder, Compression};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+/// Responses smaller than this aren't worth compressing.
+const MIN_COMPRESS_BYTES: u64 = 1024;
+/// Brotli quality level. Higher levels are too slow to compress responses
+/// as they're proxied.
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window size, as the base 2 logarithm of bytes.
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_BYTES: usize = 4096;
+
+/// Content coding used to compress a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+	Brotli,
+	Gzip,
+}
+
+impl Encoding {
+	/// All encodings, in order of preference when the client has none.
+	const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+	fn name(self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Gzip => "gzip",
+		}
+	}
+
+	/// Suffix added to strong ETags of compressed responses, since they're a
+	/// different representation than the uncompressed response.
+	fn etag_suffix(self) -> &'static str {
+		match self {
+			Encoding::Brotli => "-br",
+			Encoding::Gzip => "-gzip",
+		}
+	}
+}
+
+/// What was negotiated for a request, used to compress its response.
+pub struct Negotiation {
+	encoding: Option<Encoding>,
+	/// Encoding of the compressed ETag the client sent a conditional request for.
+	etag_encoding: Option<Encoding>,
+}
+
+/// Negotiates compression for the request. Conditional headers are rewritten
+/// to the server's uncompressed ETags, so the server can validate them.
+pub fn negotiate(req: &mut Request<Body>) -> Negotiation {
+	let encoding = if req.method() == Method::GET {
+		let accept_encoding = req
+			.headers()
+			.get_all(header::ACCEPT_ENCODING)
+			.iter()
+			.filter_map(|h| h.to_str().ok())
+			.collect::<Vec<_>>()
+			.join(",");
+		preferred_encoding(&accept_encoding)
+	} else {
+		None
+	};
+
+	let mut etag_encoding = None;
+	for name in [header::IF_NONE_MATCH, header::IF_MATCH] {
+		if let Some(v) = req.headers().get(&name).and_then(|h| h.to_str().ok()) {
+			let mut stripped = v.to_string();
+			for e in Encoding::ALL {
+				let s = stripped.replace(&format!("{}\"", e.etag_suffix()), "\"");
+				if s != stripped {
+					etag_encoding = Some(e);
+					stripped = s;
+				}
+			}
+			if stripped != v {
+				if let Ok(v) = HeaderValue::from_str(&stripped) {
+					req.headers_mut().insert(name, v);
+				}
+			}
+		}
+	}
+
+	Negotiation {
+		encoding,
+		etag_encoding,
+	}
+}
+
+/// Compresses the response with the negotiated encoding if the client
+/// accepts one and the content is worth compressing.
+pub fn compress(negotiation: &Negotiation, mut res: Response<Body>) -> Response<Body> {
+	let encoding = match negotiation.encoding {
+		Some(e) => e,
+		None => return res,
+	};
+
+	// the client's compressed ETag matched, so keep telling it about that one
+	if res.status() == StatusCode::NOT_MODIFIED {
+		if let Some(e) = negotiation.etag_encoding {
+			add_etag_suffix(res.headers_mut(), e);
+		}
+		return res;
+	}
+
+	if !should_compress(&res) {
+		return res;
+	}
+
+	let headers = res.headers_mut();
+	headers.remove(header::CONTENT_LENGTH);
+	headers.insert(
+		header::CONTENT_ENCODING,
+		HeaderValue::from_static(encoding.name()),
+	);
+	headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+	add_etag_suffix(headers, encoding);
+
+	let (parts, body) = res.into_parts();
+	Response::from_parts(parts, compress_body(body, encoding))
+}
+
+/// Picks the encoding the client prefers from its `Accept-Encoding` header,
+/// preferring brotli when it accepts several equally.
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+	let mut qualities = [None; Encoding::ALL.len()];
+	let mut wildcard = None;
+	for e in accept_encoding.split(',') {
+		let mut parts = e.split(';').map(|p| p.trim());
+		let coding = parts.next().unwrap_or_default();
+		let q = parts
+			.find_map(|p| p.strip_prefix("q="))
+			.and_then(|q| q.parse::<f32>().ok())
+			.unwrap_or(1.0);
+
+		if coding == "*" {
+			wildcard = Some(q);
+		} else if let Some(i) = Encoding::ALL
+			.iter()
+			.position(|e| coding.eq_ignore_ascii_case(e.name()))
+		{
+			qualities[i] = Some(q);
+		}
+	}
+
+	let mut best: Option<(Encoding, f32)> = None;
+	for (e, q) in Encoding::ALL.into_iter().zip(qualities) {
+		let q = match q.or(wildcard) {
+			Some(q) if q > 0.0 => q,
+			_ => continue,
+		};
+		if best.map(|(_, b)| q > b).unwrap_or(true) {
+			best = Some((e, q));
+		}
+	}
+
+	best.map(|(e, _)| e)
+}
+
+fn should_compress(res: &Response<Body>) -> bool {
+	let headers = res.headers();
+	if res.status() != StatusCode::OK || headers.contains_key(header::CONTENT_ENCODING) {
+		return false;
+	}
+
+	// ranges refer to the uncompressed content, so compressing would
+	// break clients that resume from a byte offset
+	let accepts_ranges = headers
+		.get_all(header::ACCEPT_RANGES)
+		.iter()
+		.filter_map(|h| h.to_str().ok())
+		.any(|h| !h.trim().eq_ignore_ascii_case("none"));
+	if accepts_ranges {
+		return false;
+	}
+
+	let no_transform = headers
+		.get_all(header::CACHE_CONTROL)
+		.iter()
+		.filter_map(|h| h.to_str().ok())
+		.any(|h| h.to_ascii_lowercase().contains("no-transform"));
+	if no_transform {
+		return false;
+	}
+
+	let too_small = headers
+		.get(header::CONTENT_LENGTH)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.parse::<u64>().ok())
+		.map(|len| len < MIN_COMPRESS_BYTES)
+		.unwrap_or(false);
+	if too_small {
+		return false;
+	}
+
+	headers
+		.get(header::CONTENT_TYPE)
+		.and_then(|h| h.to_str().ok())
+		.map(is_compressible_type)
+		.unwrap_or(false)
+}
+
+fn is_compressible_type(content_type: &str) -> bool {
+	let mime = content_type
+		.split(';')
+		.next()
+		.unwrap_or_default()
+		.trim()
+		.to_ascii_lowercase();
+
+	match mime.as_str() {
+		// streamed, so compression would hold back events
+		"text/event-stream" => false,
+		"application/javascript"
+		| "application/json"
+		| "application/xml"
+		| "application/wasm"
+		| "image/svg+xml" => true,
+		m => m.starts_with("text/") || m.ends_with("+json") || m.ends_with("+xml"),
+	}
+}
+
+/// Adds the encoding's suffix to a strong ETag. Weak ETags are left as they
+/// are, since they only promise semantic equivalence.
+fn add_etag_suffix(headers: &mut HeaderMap, encoding: Encoding) {
+	let etag = match headers.get(header::ETAG).and_then(|h| h.to_str().ok()) {
+		Some(e) if e.starts_with('"') && e.ends_with('"') && e.len() >= 2 => e,
+		_ => return,
+	};
+
+	let suffixed = format!("{}{}\"", &etag[..etag.len() - 1], encoding.etag_suffix());
+	if let Ok(v) = HeaderValue::from_str(&suffixed) {
+		headers.insert(header::ETAG, v);
+	}
+}
+
+/// Streaming encoder that writes compressed data to a buffer.
+enum Encoder {
+	Gzip(GzEncoder<Vec<u8>>),
+	Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+	fn new(encoding: Encoding) -> Self {
+		match encoding {
+			Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+			Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+				Vec::new(),
+				BROTLI_BUFFER_BYTES,
+				BROTLI_QUALITY,
+				BROTLI_WINDOW_BITS,
+			))),
+		}
+	}
+
+	fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+		match self {
+			Encoder::Gzip(e) => e.write_all(data),
+			Encoder::Brotli(e) => e.write_all(data),
+		}
+	}
+
+	fn get_mut(&mut self) -> &mut Vec<u8> {
+		match self {
+			Encoder::Gzip(e) => e.get_mut(),
+			Encoder::Brotli(e) => e.get_mut(),
+		}
+	}
+
+	fn finish(self) -> io::Result<Vec<u8>> {
+		match self {
+			Encoder::Gzip(e) => e.finish(),
+			Encoder::Brotli(e) => Ok(e.into_inner()),
+		}
+	}
+}
+
+/// Compresses the body as it streams through.
+fn compress_body(mut body: Body, encoding: Encoding) -> Body {
+	let (mut tx, rx) = Body::channel();
+	tokio::spawn(async move {
+		let mut encoder = Encoder::new(encoding);
+		while let Some(chunk) = body.data().await {
+			let written = chunk.ok().map(|c| encoder.write_all(&c));
+			if !matches!(written, Some(Ok(()))) {
+				tx.abort();
+				return;
+			}
+
+			let compressed = std::mem::take(encoder.get_mut());
+			if !compressed.is_empty() && tx.send_data(compressed.into()).await.is_err() {
+				return;
+			}
+		}
+
+		match encoder.finish() {
+			Ok(compressed) => {
+				let _ = tx.send_data(compressed.into()).await;
+			}
+			Err(_) => tx.abort(),
+		}
+	});
+
+	rx
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_preferred_encoding() {
+		assert_eq!(preferred_encoding("gzip"), Some(Encoding::Gzip));
+		assert_eq!(
+			preferred_encoding("gzip, deflate, br"),
+			Some(Encoding::Brotli)
+		);
+		assert_eq!(
+			preferred_encoding("br;q=0.5, GZIP;q=1.0"),
+			Some(Encoding::Gzip)
+		);
+		assert_eq!(preferred_encoding("br;q=0, *"), Some(Encoding::Gzip));
+		assert_eq!(preferred_encoding("*"), Some(Encoding::Brotli));
+		assert_eq!(preferred_encoding(""), None);
+		assert_eq!(preferred_encoding("deflate"), None);
+		assert_eq!(preferred_encoding("gzip;q=0"), None);
+		assert_eq!(preferred_encoding("identity"), None);
+	}
+
+	#[tokio::test]
+	async fn test_compress() {
+		let mut req = Request::builder()
+			.header(header::ACCEPT_ENCODING, "gzip")
+			.header(header::IF_NONE_MATCH, "\"abc-gzip\", W/\"def\"")
+			.body(Body::empty())
+			.unwrap();
+		let negotiation = negotiate(&mut req);
+		assert_eq!(negotiation.encoding, Some(Encoding::Gzip));
+		assert_eq!(negotiation.etag_encoding, Some(Encoding::Gzip));
+		assert_eq!(req.headers()[header::IF_NONE_MATCH], "\"abc\", W/\"def\"");
+
+		let res = Response::builder()
+			.status(StatusCode::NOT_MODIFIED)
+			.header(header::ETAG, "\"abc\"")
+			.body(Body::empty())
+			.unwrap();
+		let res = compress(&negotiation, res);
+		assert_eq!(res.headers()[header::ETAG], "\"abc-gzip\"");
+
+		let res = Response::builder()
+			.header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+			.header(header::ETAG, "W/\"abc\"")
+			.body(Body::from("hello world"))
+			.unwrap();
+		let res = compress(&negotiation, res);
+		assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+		assert_eq!(res.headers()[header::ETAG], "W/\"abc\"");
+
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		let mut decompressed = String::new();
+		std::io::Read::read_to_string(
+			&mut flate2::read::GzDecoder::new(&body[..]),
+			&mut decompressed,
+		)
+		.unwrap();
+		assert_eq!(decompressed, "hello world");
+	}
+
+	#[tokio::test]
+	async fn test_compress_brotli() {
+		let mut req = Request::builder()
+			.header(header::ACCEPT_ENCODING, "gzip, br")
+			.body(Body::empty())
+			.unwrap();
+		let negotiation = negotiate(&mut req);
+
+		let res = Response::builder()
+			.header(header::CONTENT_TYPE, "application/javascript")
+			.header(header::ETAG, "\"abc\"")
+			.body(Body::from("hello world"))
+			.unwrap();
+		let res = compress(&negotiation, res);
+		assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+		assert_eq!(res.headers()[header::ETAG], "\"abc-br\"");
+
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		let mut decompressed = String::new();
+		std::io::Read::read_to_string(
+			&mut brotli::Decompressor::new(&body[..], BROTLI_BUFFER_BYTES),
+			&mut decompressed,
+		)
+		.unwrap();
+		assert_eq!(decompressed, "hello world");
+
+		let res = Response::builder()
+			.header(header::CONTENT_TYPE, "text/plain")
+			.header(header::ACCEPT_RANGES, "bytes")
+			.body(Body::from("hello world"))
+			.unwrap();
+		let res = compress(&negotiation, res);
+		assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+	}
+}
@@ -0,0 +1,187 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use super::ConnectionManager;
+
+/// Liveness probe, which answers as long as serve-web is able to handle requests.
+pub const HEALTHZ_PATH: &str = "/_vscode-cli/healthz";
+/// Readiness probe, which succeeds once the latest release is downloaded and
+/// its server is running.
+pub const READYZ_PATH: &str = "/_vscode-cli/readyz";
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+	Ok,
+	Ready,
+	NotReady,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheState {
+	/// Commits in the download cache, most recently used first.
+	pub cached: Vec<String>,
+	pub downloading: Vec<String>,
+	/// Commits which are downloaded, but whose server hasn't started yet.
+	pub starting: Vec<String>,
+	pub running: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthResponse {
+	pub status: Status,
+	/// Commit served to new clients, if it's known.
+	pub latest_commit: Option<String>,
+	pub cache: CacheState,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+/// Handles the liveness probe. This doesn't check for updates or start any
+/// server, so the latest commit is only reported once it's been resolved.
+pub fn handle_healthz(cm: &ConnectionManager) -> Response<Body> {
+	json_response(
+		StatusCode::OK,
+		&HealthResponse {
+			status: Status::Ok,
+			latest_commit: cm.peek_latest_commit(),
+			cache: cache_state(cm),
+			error: None,
+		},
+	)
+}
+
+/// Handles the readiness probe. This resolves the latest release, but doesn't
+/// download or start it.
+pub async fn handle_readyz(cm: &ConnectionManager) -> Response<Body> {
+	let cache = cache_state(cm);
+	let (latest_commit, error) = match cm.get_latest_release().await {
+		Ok(r) => (Some(r.commit), None),
+		Err(e) => (cm.peek_latest_commit(), Some(e.to_string())),
+	};
+
+	let ready = error.is_none()
+		&& latest_commit
+			.as_ref()
+			.map(|c| cache.cached.contains(c) && cache.running.contains(c))
+			.unwrap_or(false);
+
+	json_response(
+		if ready {
+			StatusCode::OK
+		} else {
+			StatusCode::SERVICE_UNAVAILABLE
+		},
+		&HealthResponse {
+			status: if ready {
+				Status::Ready
+			} else {
+				Status::NotReady
+			},
+			latest_commit,
+			cache,
+			error,
+		},
+	)
+}
+
+fn cache_state(cm: &ConnectionManager) -> CacheState {
+	let mut state = CacheState {
+		cached: cm.cache.get(),
+		downloading: vec![],
+		starting: vec![],
+		running: vec![],
+	};
+
+	for v in cm.list_versions() {
+		let list = if v.started.is_some() {
+			&mut state.running
+		} else if v.downloaded {
+			&mut state.starting
+		} else {
+			&mut state.downloading
+		};
+		// servers for different users may run the same commit
+		if !list.contains(&v.commit) {
+			list.push(v.commit);
+		}
+	}
+
+	state
+}
+
+fn json_response(status: StatusCode, value: &HealthResponse) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header("Content-Type", "application/json")
+		.header("Cache-Control", "no-store")
+		.body(Body::from(serde_json::to_vec(value).unwrap()))
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+
+	const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+	async fn read_json(res: Response<Body>) -> serde_json::Value {
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		serde_json::from_slice(&body).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_readyz() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--quality", "stable", "--commit", COMMIT]);
+
+		let res = handle_readyz(&cm).await;
+		assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+		let json = read_json(res).await;
+		assert_eq!(json["status"], "not_ready");
+		assert_eq!(json["latest_commit"], COMMIT);
+		// the probe doesn't download or start the release itself
+		assert!(cm.list_versions().is_empty());
+
+		cm.insert_test_download(COMMIT);
+		let res = handle_readyz(&cm).await;
+		assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+		let (counter, _) = tokio::sync::watch::channel(0);
+		cm.insert_test_version(COMMIT, Some(Arc::new(counter)));
+		let res = handle_readyz(&cm).await;
+		assert_eq!(res.status(), StatusCode::OK);
+		let json = read_json(res).await;
+		assert_eq!(json["status"], "ready");
+		assert_eq!(json["cache"]["running"], serde_json::json!([COMMIT]));
+	}
+
+	#[tokio::test]
+	async fn test_healthz() {
+		let (cm, _dir) = ConnectionManager::new_test(&["--quality", "stable", "--commit", COMMIT]);
+
+		let res = handle_healthz(&cm);
+		assert_eq!(res.status(), StatusCode::OK);
+		let json = read_json(res).await;
+		assert_eq!(json["status"], "ok");
+		assert_eq!(json["cache"]["cached"], serde_json::json!([]));
+		assert!(cm.list_versions().is_empty());
+
+		// a downloaded release isn't started by the probe either
+		cm.insert_test_download(COMMIT);
+		let res = handle_healthz(&cm);
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(
+			read_json(res).await["cache"]["cached"],
+			serde_json::json!([COMMIT])
+		);
+		tokio::task::yield_now().await;
+		assert!(cm.list_versions().is_empty());
+	}
+}
@@ -0,0 +1,299 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::options::Quality;
+use crate::util::auth_lockout::AuthLockout;
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Counters for the serve-web server. Gauges for running servers and
+/// authentication counters are not stored here, but are read from the
+/// connection manager when rendering.
+#[derive(Default)]
+pub struct Metrics {
+	proxied_requests: AtomicU64,
+	websocket_connections: AtomicU64,
+	active_websockets: AtomicU64,
+	downloads: AtomicU64,
+	download_failures: AtomicU64,
+	download_millis: AtomicU64,
+}
+
+/// State of a single server version at the time metrics are collected.
+pub struct VersionMetrics {
+	pub quality: Quality,
+	pub commit: String,
+	pub user: Option<String>,
+	/// Whether the server has started and is accepting connections.
+	pub running: bool,
+	/// Number of open connections to the server.
+	pub connections: usize,
+}
+
+/// Guard for an open websocket, which is counted as active until dropped.
+pub struct WebsocketGuard(Arc<Metrics>);
+
+impl Drop for WebsocketGuard {
+	fn drop(&mut self) {
+		self.0.active_websockets.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+impl Metrics {
+	pub fn record_proxied_request(&self) {
+		self.proxied_requests.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn record_websocket(self: &Arc<Self>) -> WebsocketGuard {
+		self.websocket_connections.fetch_add(1, Ordering::Relaxed);
+		self.active_websockets.fetch_add(1, Ordering::Relaxed);
+		WebsocketGuard(self.clone())
+	}
+
+	pub fn record_download(&self, duration: Duration, succeeded: bool) {
+		self.downloads.fetch_add(1, Ordering::Relaxed);
+		self.download_millis
+			.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+		if !succeeded {
+			self.download_failures.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Renders the metrics in the Prometheus text format.
+	pub fn render(&self, versions: &[VersionMetrics], auth_lockout: &AuthLockout) -> String {
+		let mut out = String::new();
+		let single = |out: &mut String, name: &str, kind: &str, help: &str, v: u64| {
+			write_header(out, name, kind, help);
+			writeln!(out, "{} {}", name, v).unwrap();
+		};
+
+		write_header(
+			&mut out,
+			"serve_web_running_servers",
+			"gauge",
+			"Whether a server version is running.",
+		);
+		for v in versions {
+			writeln!(
+				out,
+				"serve_web_running_servers{} {}",
+				version_labels(v),
+				u8::from(v.running)
+			)
+			.unwrap();
+		}
+
+		write_header(
+			&mut out,
+			"serve_web_active_connections",
+			"gauge",
+			"Number of open connections to a server version.",
+		);
+		for v in versions.iter().filter(|v| v.running) {
+			writeln!(
+				out,
+				"serve_web_active_connections{} {}",
+				version_labels(v),
+				v.connections
+			)
+			.unwrap();
+		}
+
+		single(
+			&mut out,
+			"serve_web_proxied_requests_total",
+			"counter",
+			"Number of HTTP requests proxied to servers.",
+			self.proxied_requests.load(Ordering::Relaxed),
+		);
+		single(
+			&mut out,
+			"serve_web_websocket_connections_total",
+			"counter",
+			"Number of websocket connections proxied to servers.",
+			self.websocket_connections.load(Ordering::Relaxed),
+		);
+		single(
+			&mut out,
+			"serve_web_active_websockets",
+			"gauge",
+			"Number of currently open websocket connections.",
+			self.active_websockets.load(Ordering::Relaxed),
+		);
+		single(
+			&mut out,
+			"serve_web_downloads_total",
+			"counter",
+			"Number of server downloads attempted.",
+			self.downloads.load(Ordering::Relaxed),
+		);
+		single(
+			&mut out,
+			"serve_web_download_failures_total",
+			"counter",
+			"Number of server downloads that failed.",
+			self.download_failures.load(Ordering::Relaxed),
+		);
+		single(
+			&mut out,
+			"serve_web_auth_failures_total",
+			"counter",
+			"Number of failed authentication attempts.",
+			auth_lockout.failures(),
+		);
+		single(
+			&mut out,
+			"serve_web_auth_lockouts_total",
+			"counter",
+			"Number of times a client was locked out after failed authentication attempts.",
+			auth_lockout.lockouts(),
+		);
+
+		write_header(
+			&mut out,
+			"serve_web_download_duration_seconds_total",
+			"counter",
+			"Total time spent downloading servers.",
+		);
+		writeln!(
+			out,
+			"serve_web_download_duration_seconds_total {:.3}",
+			self.download_millis.load(Ordering::Relaxed) as f64 / 1000.0
+		)
+		.unwrap();
+
+		out
+	}
+}
+
+/// Merges the metrics of each user's servers for the same version, for when
+/// metrics are served to clients that shouldn't learn who is using the server.
+pub fn without_users(versions: Vec<VersionMetrics>) -> Vec<VersionMetrics> {
+	let mut merged: Vec<VersionMetrics> = Vec::with_capacity(versions.len());
+	for v in versions {
+		match merged
+			.iter_mut()
+			.find(|m| m.quality == v.quality && m.commit == v.commit)
+		{
+			Some(m) => {
+				m.running |= v.running;
+				m.connections += v.connections;
+			}
+			None => merged.push(VersionMetrics { user: None, ..v }),
+		}
+	}
+	merged
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP {} {}", name, help).unwrap();
+	writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn version_labels(v: &VersionMetrics) -> String {
+	let mut labels = format!(
+		"{{quality=\"{}\",commit=\"{}\"",
+		v.quality.get_machine_name(),
+		v.commit
+	);
+	// user names are restricted to characters that don't need escaping
+	if let Some(u) = &v.user {
+		write!(labels, ",user=\"{}\"", u).unwrap();
+	}
+	labels.push('}');
+	labels
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let metrics = Arc::new(Metrics::default());
+		metrics.record_proxied_request();
+		metrics.record_download(Duration::from_millis(1500), false);
+		let auth_lockout = AuthLockout::new(crate::log::Logger::test());
+		auth_lockout.record_failure(None);
+		for _ in 0..6 {
+			auth_lockout.record_failure(Some([192, 0, 2, 1].into()));
+		}
+		let ws = metrics.record_websocket();
+
+		let out = metrics.render(
+			&[
+				VersionMetrics {
+					quality: Quality::Stable,
+					commit: "abc".to_string(),
+					user: None,
+					running: true,
+					connections: 3,
+				},
+				VersionMetrics {
+					quality: Quality::Insiders,
+					commit: "def".to_string(),
+					user: None,
+					running: false,
+					connections: 0,
+				},
+				VersionMetrics {
+					quality: Quality::Stable,
+					commit: "abc".to_string(),
+					user: Some("alice".to_string()),
+					running: true,
+					connections: 1,
+				},
+			],
+			&auth_lockout,
+		);
+
+		assert!(out.contains("serve_web_running_servers{quality=\"stable\",commit=\"abc\"} 1\n"));
+		assert!(out.contains("serve_web_running_servers{quality=\"insiders\",commit=\"def\"} 0\n"));
+		assert!(out.contains("serve_web_active_connections{quality=\"stable\",commit=\"abc\"} 3\n"));
+		assert!(!out.contains("serve_web_active_connections{quality=\"insiders\""));
+		assert!(out.contains(
+			"serve_web_active_connections{quality=\"stable\",commit=\"abc\",user=\"alice\"} 1\n"
+		));
+		assert!(out.contains("serve_web_proxied_requests_total 1\n"));
+		assert!(out.contains("serve_web_active_websockets 1\n"));
+		assert!(out.contains("serve_web_download_failures_total 1\n"));
+		assert!(out.contains("serve_web_download_duration_seconds_total 1.500\n"));
+		assert!(out.contains("serve_web_auth_failures_total 7\n"));
+		assert!(out.contains("serve_web_auth_lockouts_total 1\n"));
+
+		let out = metrics.render(
+			&without_users(vec![
+				VersionMetrics {
+					quality: Quality::Stable,
+					commit: "abc".to_string(),
+					user: Some("alice".to_string()),
+					running: true,
+					connections: 1,
+				},
+				VersionMetrics {
+					quality: Quality::Stable,
+					commit: "abc".to_string(),
+					user: Some("bob".to_string()),
+					running: false,
+					connections: 0,
+				},
+			]),
+			&auth_lockout,
+		);
+		assert!(!out.contains("alice"));
+		assert!(out.contains("serve_web_running_servers{quality=\"stable\",commit=\"abc\"} 1\n"));
+
+		drop(ws);
+		assert!(metrics
+			.render(&[], &auth_lockout)
+			.contains("serve_web_active_websockets 0\n"));
+	}
+}
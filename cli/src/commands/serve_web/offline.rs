@@ -0,0 +1,199 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::download_cache::DownloadCache;
+use crate::log;
+use crate::options::Quality;
+use crate::update_service::unzip_downloaded_release;
+use crate::util::errors::{wrap, AnyError, CodeError};
+use crate::util::io::SilentCopyProgress;
+
+use super::is_commit_hash;
+
+/// Subset of the server's product.json needed to identify the build.
+#[derive(Deserialize)]
+struct ServerProduct {
+	commit: Option<String>,
+	quality: Option<String>,
+}
+
+/// Extracts a local server archive into the download cache so it can be
+/// served without contacting the update service. Returns the quality and
+/// commit of the server, read from its product.json.
+pub async fn seed_cache_from_archive(
+	log: &log::Logger,
+	cache: &DownloadCache,
+	archive: &Path,
+) -> Result<(Quality, String), AnyError> {
+	if !archive.exists() {
+		return Err(CodeError::InvalidServerArchive(format!(
+			"{} does not exist",
+			archive.display()
+		))
+		.into());
+	}
+
+	std::fs::create_dir_all(cache.path())
+		.map_err(|e| wrap(e, "error creating server directory"))?;
+	// extract next to the cache so the result can be renamed into place
+	let extracted = tempfile::tempdir_in(cache.path())
+		.map_err(|e| wrap(e, "error creating server directory"))?;
+
+	info!(log, "Extracting server from {}", archive.display());
+	unzip_downloaded_release(archive, extracted.path(), SilentCopyProgress())?;
+
+	let product_path = extracted.path().join("product.json");
+	let product: ServerProduct = std::fs::read(&product_path)
+		.ok()
+		.and_then(|b| serde_json::from_slice(&b).ok())
+		.ok_or_else(|| {
+			CodeError::InvalidServerArchive("archive does not contain a product.json".to_string())
+		})?;
+
+	// the commit names the cache directory, so it must not be a path
+	let commit = product
+		.commit
+		.filter(|c| is_commit_hash(c))
+		.ok_or_else(|| {
+			CodeError::InvalidServerArchive(
+				"product.json does not contain a valid commit".to_string(),
+			)
+		})?;
+	let quality = product
+		.quality
+		.as_deref()
+		.ok_or_else(|| "product.json does not contain a quality".to_string())
+		.and_then(Quality::try_from)
+		.map_err(CodeError::InvalidServerArchive)?;
+
+	if cache.exists(&commit).is_some() {
+		debug!(log, "Server {} is already in the cache", commit);
+		return Ok((quality, commit));
+	}
+
+	let extracted_path = extracted.path().to_owned();
+	cache
+		.create(&commit, |target_dir| async move {
+			std::fs::remove_dir(&target_dir)
+				.and_then(|_| std::fs::rename(&extracted_path, &target_dir))
+				.map_err(|e| wrap(e, "error moving extracted server").into())
+		})
+		.await?;
+
+	info!(log, "Using server {} from archive", commit);
+	Ok((quality, commit))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use super::*;
+
+	const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+	/// Server archives have everything in a top-level directory.
+	const ROOT: &str = "vscode-server-linux-x64-web";
+
+	/// Names and contents of files in an archive.
+	type Files<'a> = Vec<(&'a str, &'a [u8])>;
+
+	fn product_json(commit: Option<&str>, quality: &str) -> Vec<u8> {
+		serde_json::json!({ "commit": commit, "quality": quality })
+			.to_string()
+			.into_bytes()
+	}
+
+	fn write_tar_gz(path: &Path, files: &[(&str, &[u8])]) {
+		let file = std::fs::File::create(path).unwrap();
+		let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+		let mut tar = tar::Builder::new(gz);
+		for (name, contents) in files {
+			let mut header = tar::Header::new_gnu();
+			header.set_size(contents.len() as u64);
+			header.set_mode(0o644);
+			header.set_cksum();
+			tar.append_data(&mut header, format!("{}/{}", ROOT, name), *contents)
+				.unwrap();
+		}
+		tar.into_inner().unwrap().finish().unwrap();
+	}
+
+	fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+		let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+		for (name, contents) in files {
+			zip.start_file(format!("{}/{}", ROOT, name), Default::default())
+				.unwrap();
+			zip.write_all(contents).unwrap();
+		}
+		zip.finish().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_seed_cache_from_archive() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(dir.path().join("cache"));
+		let files: &[(&str, &[u8])] = &[
+			("product.json", &product_json(Some(COMMIT), "insider")),
+			("bin/code-server", b"#!/bin/sh"),
+		];
+
+		let tar_gz = dir.path().join("server.tar.gz");
+		write_tar_gz(&tar_gz, files);
+		let (quality, commit) = seed_cache_from_archive(&log::Logger::test(), &cache, &tar_gz)
+			.await
+			.unwrap();
+		assert_eq!(quality, Quality::Insiders);
+		assert_eq!(commit, COMMIT);
+		let server = cache.exists(COMMIT).unwrap();
+		assert!(server.join("bin/code-server").exists());
+
+		cache.delete(COMMIT).unwrap();
+		let zip = dir.path().join("server.zip");
+		write_zip(&zip, files);
+		let (quality, commit) = seed_cache_from_archive(&log::Logger::test(), &cache, &zip)
+			.await
+			.unwrap();
+		assert_eq!(quality, Quality::Insiders);
+		assert_eq!(commit, COMMIT);
+		assert!(cache.exists(COMMIT).is_some());
+	}
+
+	#[tokio::test]
+	async fn test_seed_cache_from_invalid_archive() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(dir.path().join("cache"));
+		let no_commit = product_json(None, "stable");
+		let bad_commit = product_json(Some("../../escape"), "stable");
+		let bad_quality = product_json(Some(COMMIT), "nightly");
+
+		let cases: Vec<(&str, Files)> = vec![
+			("missing.tar.gz", vec![]),
+			("no-product.tar.gz", vec![("README.md", b"hi")]),
+			("no-commit.tar.gz", vec![("product.json", &no_commit)]),
+			("bad-commit.zip", vec![("product.json", &bad_commit)]),
+			("bad-quality.tar.gz", vec![("product.json", &bad_quality)]),
+		];
+		for (name, files) in cases {
+			let path = dir.path().join(name);
+			if name.ends_with(".zip") {
+				write_zip(&path, &files);
+			} else if !files.is_empty() {
+				write_tar_gz(&path, &files);
+			}
+
+			match seed_cache_from_archive(&log::Logger::test(), &cache, &path).await {
+				Err(AnyError::CodeError(CodeError::InvalidServerArchive(_))) => {}
+				Err(e) => panic!("unexpected error for {}: {}", name, e),
+				Ok(_) => panic!("expected an error for {}", name),
+			}
+		}
+		assert!(cache.get().is_empty());
+	}
+}
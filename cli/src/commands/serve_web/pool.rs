@@ -0,0 +1,289 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::future::poll_fn;
+use futures::FutureExt;
+use hyper::client::conn::SendRequest;
+use hyper::Body;
+
+use super::ConnectionHandle;
+
+/// Maximum number of idle connections kept open to each server.
+const MAX_IDLE_CONNECTIONS_PER_SERVER: usize = 32;
+/// Time after which idle connections are closed. This is less than the 5
+/// second default keep-alive timeout of Node's HTTP server, so that we don't
+/// send requests on connections the server is about to close.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(4);
+
+struct IdleConnection {
+	sender: SendRequest<Body>,
+	idle_since: Instant,
+}
+
+impl IdleConnection {
+	/// Gets whether the connection is still open and hasn't timed out.
+	fn is_reusable(&mut self) -> bool {
+		self.idle_since.elapsed() < IDLE_TIMEOUT
+			&& matches!(
+				poll_fn(|cx| self.sender.poll_ready(cx)).now_or_never(),
+				Some(Ok(()))
+			)
+	}
+}
+
+/// Pool of HTTP/1.1 keep-alive connections to the running servers, keyed by
+/// the socket path of each server. Idle connections don't count as clients of
+/// the server, so they don't prevent it from being stopped.
+#[derive(Default)]
+pub struct ConnectionPool {
+	idle: Mutex<HashMap<PathBuf, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+	pub fn new() -> Arc<Self> {
+		let pool = Arc::new(Self::default());
+		tokio::spawn(evict_idle_connections(Arc::downgrade(&pool)));
+		pool
+	}
+
+	/// Takes an idle connection to the server, if there is one.
+	pub fn take(&self, socket_path: &Path) -> Option<SendRequest<Body>> {
+		let mut idle = self.idle.lock().unwrap();
+		let connections = idle.get_mut(socket_path)?;
+		// most recently used connections are least likely to have been closed
+		while let Some(mut c) = connections.pop() {
+			if c.is_reusable() {
+				return Some(c.sender);
+			}
+		}
+
+		None
+	}
+
+	/// Returns the connection to the pool once the response that's being sent
+	/// on it has been read. The handle is held until then, since the server is
+	/// in use for as long as the response is.
+	pub fn release(
+		self: &Arc<Self>,
+		socket_path: PathBuf,
+		mut sender: SendRequest<Body>,
+		handle: ConnectionHandle,
+	) {
+		let pool = self.clone();
+		tokio::spawn(async move {
+			let ready = poll_fn(|cx| sender.poll_ready(cx)).await;
+			drop(handle);
+			if ready.is_ok() {
+				pool.put(socket_path, sender);
+			}
+		});
+	}
+
+	fn put(&self, socket_path: PathBuf, sender: SendRequest<Body>) {
+		let mut idle = self.idle.lock().unwrap();
+		let connections = idle.entry(socket_path).or_default();
+		if connections.len() < MAX_IDLE_CONNECTIONS_PER_SERVER {
+			connections.push(IdleConnection {
+				sender,
+				idle_since: Instant::now(),
+			});
+		}
+	}
+
+	/// Closes connections that have been idle for too long, or which the
+	/// server closed, such as when it exits.
+	fn evict_idle(&self) {
+		let mut idle = self.idle.lock().unwrap();
+		for connections in idle.values_mut() {
+			connections.retain_mut(|c| c.is_reusable());
+		}
+		idle.retain(|_, connections| !connections.is_empty());
+	}
+}
+
+async fn evict_idle_connections(pool: Weak<ConnectionPool>) {
+	let mut interval = tokio::time::interval(IDLE_TIMEOUT);
+	loop {
+		interval.tick().await;
+		match pool.upgrade() {
+			Some(p) => p.evict_idle(),
+			None => return,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::convert::Infallible;
+
+	use hyper::service::service_fn;
+	use hyper::{Request, Response};
+	use tokio::task::JoinHandle;
+
+	use super::*;
+
+	/// Keep-alive connection to an in-memory server.
+	struct TestConnection {
+		sender: SendRequest<Body>,
+		server: JoinHandle<()>,
+		client: JoinHandle<()>,
+	}
+
+	/// Connects to a server that responds with "hello".
+	async fn connect() -> TestConnection {
+		connect_with(|| Body::from("hello")).await
+	}
+
+	/// Connects to a server that responds with the bodies from `body`.
+	async fn connect_with(body: impl Fn() -> Body + Send + Sync + 'static) -> TestConnection {
+		let (client_io, server_io) = tokio::io::duplex(1024);
+		let body = Arc::new(body);
+		let server = tokio::spawn(async move {
+			let service = service_fn(move |_| {
+				let body = body();
+				async { Ok::<_, Infallible>(Response::new(body)) }
+			});
+			let _ = hyper::server::conn::Http::new()
+				.serve_connection(server_io, service)
+				.await;
+		});
+		let (mut sender, connection) = hyper::client::conn::Builder::new()
+			.handshake(client_io)
+			.await
+			.unwrap();
+		let client = tokio::spawn(async move {
+			let _ = connection.await;
+		});
+		// connections are only pooled once they're ready for another request
+		poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+
+		TestConnection {
+			sender,
+			server,
+			client,
+		}
+	}
+
+	fn idle_count(pool: &ConnectionPool, socket_path: &Path) -> usize {
+		pool.idle
+			.lock()
+			.unwrap()
+			.get(socket_path)
+			.map(|c| c.len())
+			.unwrap_or(0)
+	}
+
+	#[tokio::test]
+	async fn test_release_after_response() {
+		let pool = Arc::new(ConnectionPool::default());
+		let socket_path = PathBuf::from("/server.sock");
+		let (counter, mut counter_rx) = tokio::sync::watch::channel(0);
+		let counter = Arc::new(counter);
+
+		let (mut body_tx, body) = Body::channel();
+		let body = Mutex::new(Some(body));
+		let mut c = connect_with(move || body.lock().unwrap().take().unwrap()).await;
+		let res = c
+			.sender
+			.send_request(Request::new(Body::empty()))
+			.await
+			.unwrap();
+		pool.release(
+			socket_path.clone(),
+			c.sender,
+			ConnectionHandle::new(counter.clone()),
+		);
+
+		// the server is in use until the whole response is read
+		body_tx.send_data("hello".into()).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert_eq!(*counter_rx.borrow(), 1);
+		assert!(pool.take(&socket_path).is_none());
+
+		drop(body_tx);
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		assert_eq!(body, "hello");
+		tokio::time::timeout(Duration::from_secs(5), counter_rx.wait_for(|c| *c == 0))
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(pool.take(&socket_path).is_some());
+	}
+
+	#[tokio::test]
+	async fn test_take_skips_closed() {
+		let pool = ConnectionPool::default();
+		let socket_path = PathBuf::from("/server.sock");
+
+		let open = connect().await;
+		pool.put(socket_path.clone(), open.sender);
+		let closed = connect().await;
+		pool.put(socket_path.clone(), closed.sender);
+
+		// the server closing the connection ends the client's connection task
+		closed.server.abort();
+		closed.client.await.unwrap();
+
+		let mut sender = pool.take(&socket_path).unwrap();
+		assert!(pool.take(&socket_path).is_none());
+		let res = sender
+			.send_request(Request::new(Body::empty()))
+			.await
+			.unwrap();
+		assert_eq!(
+			hyper::body::to_bytes(res.into_body()).await.unwrap(),
+			"hello"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_max_idle_connections() {
+		let pool = ConnectionPool::default();
+		let socket_path = PathBuf::from("/server.sock");
+
+		let mut connections = vec![];
+		for _ in 0..MAX_IDLE_CONNECTIONS_PER_SERVER + 1 {
+			let c = connect().await;
+			pool.put(socket_path.clone(), c.sender);
+			connections.push((c.server, c.client));
+		}
+		assert_eq!(
+			idle_count(&pool, &socket_path),
+			MAX_IDLE_CONNECTIONS_PER_SERVER
+		);
+
+		// other servers have their own limit
+		let other_path = PathBuf::from("/other.sock");
+		pool.put(other_path.clone(), connect().await.sender);
+		assert_eq!(idle_count(&pool, &other_path), 1);
+	}
+
+	#[tokio::test]
+	async fn test_evict_idle() {
+		let pool = ConnectionPool::default();
+		let socket_path = PathBuf::from("/server.sock");
+
+		let old = connect().await;
+		pool.put(socket_path.clone(), old.sender);
+		pool.idle.lock().unwrap().get_mut(&socket_path).unwrap()[0].idle_since =
+			Instant::now() - IDLE_TIMEOUT;
+		let new = connect().await;
+		pool.put(socket_path.clone(), new.sender);
+
+		pool.evict_idle();
+		assert_eq!(idle_count(&pool, &socket_path), 1);
+
+		pool.idle.lock().unwrap().get_mut(&socket_path).unwrap()[0].idle_since =
+			Instant::now() - IDLE_TIMEOUT;
+		pool.evict_idle();
+		assert!(pool.idle.lock().unwrap().is_empty());
+		assert!(pool.take(&socket_path).is_none());
+	}
+}
@@ -0,0 +1,425 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use crate::commands::args::ServeWebArgs;
+use crate::log;
+use crate::state::LauncherPaths;
+use crate::util::command::{capture_command, capture_command_and_check_status};
+use crate::util::errors::CodeError;
+
+/// How often the certificate and key files are checked for changes.
+const CERT_RELOAD_INTERVAL_SECS: u64 = 30;
+/// Time after which an incomplete TLS handshake is abandoned.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Number of handshaked connections that may wait to be picked up by hyper.
+const ACCEPT_BACKLOG: usize = 32;
+/// Validity of the generated self-signed certificate.
+const SELF_SIGNED_VALID_DAYS: &str = "365";
+/// `openssl req` config for the self-signed certificate. This is used rather
+/// than `-subj` and `-addext`, which older OpenSSL and LibreSSL versions lack.
+const SELF_SIGNED_CONFIG: &str = "[req]
+distinguished_name = dn
+x509_extensions = ext
+prompt = no
+[dn]
+CN = localhost
+[ext]
+subjectAltName = DNS:localhost,IP:127.0.0.1,IP:::1
+";
+
+/// A TLS connection from a client.
+pub type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// TLS configuration for the web server, loaded from the `--tls-*` args.
+pub struct TlsConfig {
+	log: log::Logger,
+	cert: PathBuf,
+	key: PathBuf,
+	acceptor: TlsAcceptor,
+}
+
+impl TlsConfig {
+	/// Loads the TLS configuration, returning None if TLS was not requested.
+	pub async fn from_args(
+		log: &log::Logger,
+		paths: &LauncherPaths,
+		args: &ServeWebArgs,
+	) -> Result<Option<Self>, CodeError> {
+		let (cert, key) = match (&args.tls_cert, &args.tls_key) {
+			(Some(c), Some(k)) => (PathBuf::from(c), PathBuf::from(k)),
+			_ if args.tls_self_signed => ensure_self_signed_cert(log, paths).await?,
+			_ => return Ok(None),
+		};
+
+		let acceptor = load_acceptor(&cert, &key).await?;
+		Ok(Some(Self {
+			log: log.clone(),
+			cert,
+			key,
+			acceptor,
+		}))
+	}
+
+	/// Accepts connections on the listener, returning an incoming stream of TLS
+	/// connections that can be given to hyper. The certificate is reloaded from
+	/// disk whenever it changes.
+	pub fn listen(&self, listener: std::net::TcpListener) -> Result<TlsIncoming, CodeError> {
+		let listener = listener
+			.set_nonblocking(true)
+			.and_then(|_| TcpListener::from_std(listener))
+			.map_err(CodeError::CouldNotBindAddress)?;
+		let local_addr = listener
+			.local_addr()
+			.map_err(CodeError::CouldNotBindAddress)?;
+
+		let (acceptor_tx, acceptor_rx) = watch::channel(self.acceptor.clone());
+		let (conn_tx, conn_rx) = mpsc::channel(ACCEPT_BACKLOG);
+
+		tokio::spawn(reload_on_change(
+			self.log.clone(),
+			self.cert.clone(),
+			self.key.clone(),
+			Duration::from_secs(CERT_RELOAD_INTERVAL_SECS),
+			acceptor_tx,
+		));
+		tokio::spawn(accept_loop(
+			self.log.clone(),
+			listener,
+			acceptor_rx,
+			conn_tx,
+		));
+
+		Ok(TlsIncoming {
+			local_addr,
+			rx: conn_rx,
+		})
+	}
+}
+
+/// Stream of accepted connections which have completed their TLS handshake.
+pub struct TlsIncoming {
+	local_addr: SocketAddr,
+	rx: mpsc::Receiver<TlsStream>,
+}
+
+impl TlsIncoming {
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addr
+	}
+}
+
+impl hyper::server::accept::Accept for TlsIncoming {
+	type Conn = TlsStream;
+	type Error = std::io::Error;
+
+	fn poll_accept(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+		self.rx.poll_recv(cx).map(|c| c.map(Ok))
+	}
+}
+
+/// Accepts TCP connections and performs handshakes with the current acceptor.
+/// Handshakes are done off the accept loop so slow clients don't block others.
+async fn accept_loop(
+	log: log::Logger,
+	listener: TcpListener,
+	acceptor: watch::Receiver<TlsAcceptor>,
+	tx: mpsc::Sender<TlsStream>,
+) {
+	loop {
+		let (stream, addr) = tokio::select! {
+			r = listener.accept() => match r {
+				Ok(s) => s,
+				Err(e) => {
+					// similar to hyper's AddrIncoming, back off on errors like EMFILE
+					warning!(log, "error accepting connection: {}", e);
+					tokio::time::sleep(Duration::from_secs(1)).await;
+					continue;
+				}
+			},
+			_ = tx.closed() => return,
+		};
+
+		let acceptor = acceptor.borrow().clone();
+		let tx = tx.clone();
+		let log = log.clone();
+		tokio::spawn(async move {
+			let handshake = tokio::time::timeout(
+				Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+				acceptor.accept(stream),
+			);
+			match handshake.await {
+				Ok(Ok(s)) => {
+					let _ = tx.send(s).await;
+				}
+				Ok(Err(e)) => debug!(log, "TLS handshake with {} failed: {}", addr, e),
+				Err(_) => debug!(log, "TLS handshake with {} timed out", addr),
+			}
+		});
+	}
+}
+
+/// Polls the certificate and key for changes, replacing the acceptor when
+/// they're updated. Invalid files are logged and the previous acceptor is kept.
+async fn reload_on_change(
+	log: log::Logger,
+	cert: PathBuf,
+	key: PathBuf,
+	interval: Duration,
+	tx: watch::Sender<TlsAcceptor>,
+) {
+	let mut last_modified = get_modified_times(&cert, &key);
+	loop {
+		tokio::select! {
+			_ = tokio::time::sleep(interval) => {},
+			_ = tx.closed() => return,
+		}
+
+		let modified = get_modified_times(&cert, &key);
+		if modified == last_modified {
+			continue;
+		}
+
+		last_modified = modified;
+		match load_acceptor(&cert, &key).await {
+			Ok(a) => {
+				info!(log, "Reloaded TLS certificate from {}", cert.display());
+				tx.send_replace(a);
+			}
+			Err(e) => warning!(log, "Keeping previous TLS certificate: {}", e),
+		}
+	}
+}
+
+fn get_modified_times(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+	let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+	(modified(cert), modified(key))
+}
+
+async fn load_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, CodeError> {
+	let read = |p: &Path| {
+		let p = p.to_owned();
+		async move {
+			tokio::fs::read(&p).await.map_err(|e| {
+				CodeError::InvalidTlsCertificate(format!("could not read {}: {}", p.display(), e))
+			})
+		}
+	};
+
+	let (cert_pem, key_pem) = (read(cert).await?, read(key).await?);
+	let identity = native_tls::Identity::from_pkcs8(&cert_pem, &key_pem)
+		.map_err(|e| CodeError::InvalidTlsCertificate(e.to_string()))?;
+	let acceptor = native_tls::TlsAcceptor::new(identity)
+		.map_err(|e| CodeError::InvalidTlsCertificate(e.to_string()))?;
+	let acceptor = TlsAcceptor::from(acceptor);
+	check_acceptor(&acceptor).await?;
+
+	Ok(acceptor)
+}
+
+/// Handshakes with the acceptor in memory. A key that doesn't belong to the
+/// certificate is otherwise only noticed once clients fail to connect.
+async fn check_acceptor(acceptor: &TlsAcceptor) -> Result<(), CodeError> {
+	let connector = native_tls::TlsConnector::builder()
+		.danger_accept_invalid_certs(true)
+		.danger_accept_invalid_hostnames(true)
+		.build()
+		.map_err(|e| CodeError::InvalidTlsCertificate(e.to_string()))?;
+	let connector = tokio_native_tls::TlsConnector::from(connector);
+
+	let (client, server) = tokio::io::duplex(16 * 1024);
+	let handshake = async {
+		tokio::try_join!(
+			async { connector.connect("localhost", client).await.map(|_| ()) },
+			async { acceptor.accept(server).await.map(|_| ()) },
+		)
+	};
+	match tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), handshake).await {
+		Ok(Ok(_)) => Ok(()),
+		Ok(Err(e)) => Err(CodeError::InvalidTlsCertificate(format!(
+			"the key may not belong to the certificate: {}",
+			e
+		))),
+		Err(_) => Err(CodeError::InvalidTlsCertificate(
+			"timed out checking the certificate".to_string(),
+		)),
+	}
+}
+
+/// Gets the path to a self-signed certificate and key for localhost,
+/// generating them with `openssl` if they don't exist yet.
+async fn ensure_self_signed_cert(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+) -> Result<(PathBuf, PathBuf), CodeError> {
+	let dir = paths.root().join("serve-web-tls");
+	let cert = dir.join("self-signed-cert.pem");
+	let key = dir.join("self-signed-key.pem");
+	if cert.exists() && key.exists() {
+		return Ok((cert, key));
+	}
+
+	// openssl isn't available on Windows or in many container images
+	if capture_command("openssl", ["version"]).await.is_err() {
+		return Err(CodeError::SelfSignedCertFailed(
+			"the `openssl` command was not found".to_string(),
+		));
+	}
+
+	let config = dir.join("self-signed.cnf");
+	std::fs::create_dir_all(&dir)
+		.and_then(|_| std::fs::write(&config, SELF_SIGNED_CONFIG))
+		.map_err(|e| {
+			CodeError::SelfSignedCertFailed(format!("could not write to {}: {}", dir.display(), e))
+		})?;
+
+	info!(
+		log,
+		"Generating a self-signed certificate in {}",
+		dir.display()
+	);
+	let config_arg = config.to_string_lossy().to_string();
+	let cert_arg = cert.to_string_lossy().to_string();
+	let key_arg = key.to_string_lossy().to_string();
+	let result = capture_command_and_check_status(
+		"openssl",
+		&[
+			"req",
+			"-x509",
+			"-newkey",
+			"rsa:2048",
+			"-nodes",
+			"-sha256",
+			"-days",
+			SELF_SIGNED_VALID_DAYS,
+			"-config",
+			config_arg.as_str(),
+			"-keyout",
+			key_arg.as_str(),
+			"-out",
+			cert_arg.as_str(),
+		],
+	)
+	.await;
+	let _ = std::fs::remove_file(&config);
+
+	if let Err(e) = result {
+		// don't leave a partial pair behind to be picked up next time
+		let _ = std::fs::remove_file(&cert);
+		let _ = std::fs::remove_file(&key);
+		return Err(CodeError::SelfSignedCertFailed(e.to_string()));
+	}
+
+	Ok((cert, key))
+}
+
+// openssl is used to generate the certificates
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	/// Generates a self-signed certificate and key in a new directory.
+	async fn generate_cert() -> (tempfile::TempDir, PathBuf, PathBuf) {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let (cert, key) = ensure_self_signed_cert(&log::Logger::test(), &paths)
+			.await
+			.unwrap();
+		(dir, cert, key)
+	}
+
+	/// Handshakes with the acceptor, returning the certificate it presented.
+	async fn served_cert(acceptor: &TlsAcceptor) -> Vec<u8> {
+		let (client, server) = tokio::io::duplex(16 * 1024);
+		let connector = native_tls::TlsConnector::builder()
+			.danger_accept_invalid_certs(true)
+			.build()
+			.unwrap();
+		let connector = tokio_native_tls::TlsConnector::from(connector);
+		let (client, server) = tokio::join!(
+			connector.connect("localhost", client),
+			acceptor.accept(server)
+		);
+		server.unwrap();
+		let cert = client.unwrap().get_ref().peer_certificate().unwrap();
+		cert.unwrap().to_der().unwrap()
+	}
+
+	fn cert_der(path: &Path) -> Vec<u8> {
+		let pem = std::fs::read(path).unwrap();
+		native_tls::Certificate::from_pem(&pem)
+			.unwrap()
+			.to_der()
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_load_acceptor_invalid() {
+		let (dir, cert, key) = generate_cert().await;
+		let (_other_dir, other_cert, _) = generate_cert().await;
+		assert!(load_acceptor(&cert, &key).await.is_ok());
+
+		let garbage = dir.path().join("garbage.pem");
+		std::fs::write(&garbage, "not a certificate").unwrap();
+		let missing = dir.path().join("missing.pem");
+		for (cert, key) in [
+			(&garbage, &key),
+			(&cert, &garbage),
+			(&missing, &key),
+			// the key doesn't belong to the certificate
+			(&other_cert, &key),
+		] {
+			match load_acceptor(cert, key).await {
+				Err(CodeError::InvalidTlsCertificate(_)) => {}
+				Err(e) => panic!("unexpected error for {}: {}", cert.display(), e),
+				Ok(_) => panic!("expected an error for {}", cert.display()),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_reload_on_change() {
+		let (_dir, cert, key) = generate_cert().await;
+		let (_other_dir, other_cert, other_key) = generate_cert().await;
+
+		let acceptor = load_acceptor(&cert, &key).await.unwrap();
+		assert_eq!(served_cert(&acceptor).await, cert_der(&cert));
+
+		let (tx, mut rx) = watch::channel(acceptor);
+		tokio::spawn(reload_on_change(
+			log::Logger::test(),
+			cert.clone(),
+			key.clone(),
+			Duration::from_millis(10),
+			tx,
+		));
+
+		// invalid files are ignored, and the previous certificate is kept
+		std::fs::write(&cert, "not a certificate").unwrap();
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(!rx.has_changed().unwrap());
+
+		std::fs::copy(&other_cert, &cert).unwrap();
+		std::fs::copy(&other_key, &key).unwrap();
+		tokio::time::timeout(Duration::from_secs(5), rx.changed())
+			.await
+			.unwrap()
+			.unwrap();
+		let acceptor = rx.borrow_and_update().clone();
+		assert_eq!(served_cert(&acceptor).await, cert_der(&other_cert));
+	}
+}
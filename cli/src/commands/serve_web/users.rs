@@ -0,0 +1,60 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Path, PathBuf};
+
+use hyper::{Body, Request};
+
+/// Maximum length of a user name, which is used as a directory name.
+const MAX_USER_NAME_LEN: usize = 64;
+
+/// Returns whether the user name is safe to use as a directory name: it may
+/// only contain ASCII alphanumerics and `.`, `_`, `-`, `@`, and may not start
+/// with a `.`.
+pub fn is_valid_user_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name.len() <= MAX_USER_NAME_LEN
+		&& !name.starts_with('.')
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'))
+}
+
+/// Gets the user from the trusted header, if it's present and valid.
+pub fn user_from_header(req: &Request<Body>, header: &str) -> Option<String> {
+	req.headers()
+		.get(header)
+		.and_then(|h| h.to_str().ok())
+		.map(|h| h.trim())
+		.filter(|h| is_valid_user_name(h))
+		.map(|h| h.to_string())
+}
+
+/// Gets the user data and extensions directories for the user.
+pub fn user_dirs(users_root: &Path, user: &str) -> (PathBuf, PathBuf) {
+	let dir = users_root.join(user);
+	(dir.join("data"), dir.join("extensions"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_valid_user_name() {
+		assert!(is_valid_user_name("alice"));
+		assert!(is_valid_user_name("alice.smith@contoso.com"));
+		assert!(is_valid_user_name("bob_2-x"));
+		assert!(!is_valid_user_name(""));
+		assert!(!is_valid_user_name("."));
+		assert!(!is_valid_user_name(".."));
+		assert!(!is_valid_user_name(".hidden"));
+		assert!(!is_valid_user_name("../alice"));
+		assert!(!is_valid_user_name("alice/bob"));
+		assert!(!is_valid_user_name("alice\\bob"));
+		assert!(!is_valid_user_name("alice bob"));
+		assert!(!is_valid_user_name(&"a".repeat(MAX_USER_NAME_LEN + 1)));
+	}
+}
@@ -8,7 +8,7 @@ use base64::{engine::general_purpose as b64, Engine as _};
 use futures::{stream::FuturesUnordered, StreamExt};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::{str::FromStr, time::Duration};
+use std::{str::FromStr, sync::Arc, time::Duration};
 use sysinfo::Pid;
 use tokio::{
 	io::{AsyncBufReadExt, BufReader},
@@ -47,8 +47,10 @@ use crate::{
 	},
 	util::{
 		app_lock::AppMutex,
+		auth_lockout::AuthLockout,
 		errors::{wrap, AnyError, CodeError},
 		prereqs::PreReqChecker,
+		socket_activation::{self, ActivatedListener},
 	},
 };
 use crate::{
@@ -132,6 +134,10 @@ impl ServiceContainer for TunnelServiceContainer {
 
 pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Result<i32, AnyError> {
 	let platform = PreReqChecker::new().verify().await?;
+	let auth_lockout = args
+		.require_token
+		.is_some()
+		.then(|| Arc::new(AuthLockout::new(ctx.log.clone())));
 	let mut params = ServeStreamParams {
 		log: ctx.log,
 		launcher_paths: ctx.paths,
@@ -140,48 +146,92 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 			.require_token
 			.map(AuthRequired::VSDAWithToken)
 			.unwrap_or(AuthRequired::VSDA),
-		exit_barrier: ShutdownRequest::create_rx([ShutdownRequest::CtrlC]),
+		exit_barrier: ShutdownRequest::create_rx([
+			ShutdownRequest::CtrlC,
+			ShutdownRequest::Terminated,
+		]),
 		code_server_args: (&ctx.args).into(),
+		auth_lockout,
+		client_addr: None,
 	};
 
-	let mut listener: Box<dyn AsyncRWAccepter> = match (args.on_port, args.on_socket) {
-		(_, true) => {
-			let socket = get_socket_name();
-			let listener = listen_socket_rw_stream(&socket)
-				.await
-				.map_err(|e| wrap(e, "error listening on socket"))?;
+	let mut activated = socket_activation::take_listeners(&params.log).into_iter();
+	if activated.len() > 1 {
+		warning!(
+			params.log,
+			"Only the first of the sockets passed by systemd is used"
+		);
+	}
 
-			params
-				.log
-				.result(format!("Listening on {}", socket.display()));
+	let mut listener: Box<dyn AsyncRWAccepter> =
+		match (activated.next(), args.on_port, args.on_socket) {
+			(Some(ActivatedListener::Tcp(listener)), _, _) => {
+				let listener = listener
+					.set_nonblocking(true)
+					.and_then(|_| tokio::net::TcpListener::from_std(listener))
+					.map_err(|e| wrap(e, "error listening on activated socket"))?;
 
-			Box::new(listener)
-		}
-		(true, _) => {
-			let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-				.await
-				.map_err(|e| wrap(e, "error listening on port"))?;
+				params
+					.log
+					.result(format!("Listening on {}", listener.local_addr().unwrap()));
 
-			params
-				.log
-				.result(format!("Listening on {}", listener.local_addr().unwrap()));
+				Box::new(listener)
+			}
+			#[cfg(unix)]
+			(Some(ActivatedListener::Unix(listener)), _, _) => {
+				params.log.result("Listening on socket passed by systemd");
+				Box::new(crate::async_pipe::AsyncPipeListener::from_std(listener)?)
+			}
+			(None, _, true) => {
+				let socket = get_socket_name();
+				let listener = listen_socket_rw_stream(&socket)
+					.await
+					.map_err(|e| wrap(e, "error listening on socket"))?;
 
-			Box::new(listener)
-		}
-		_ => {
-			serve_stream(tokio::io::stdin(), tokio::io::stderr(), params).await;
-			return Ok(0);
-		}
-	};
+				params
+					.log
+					.result(format!("Listening on {}", socket.display()));
+
+				Box::new(listener)
+			}
+			(None, true, _) => {
+				let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+					.await
+					.map_err(|e| wrap(e, "error listening on port"))?;
+
+				params
+					.log
+					.result(format!("Listening on {}", listener.local_addr().unwrap()));
+
+				Box::new(listener)
+			}
+			_ => {
+				serve_stream(tokio::io::stdin(), tokio::io::stderr(), params).await;
+				return Ok(0);
+			}
+		};
 
 	let mut servers = FuturesUnordered::new();
+	let idle_timeout = Duration::from_secs(args.idle_exit_timeout.unwrap_or_default());
+	let idle_timer = tokio::time::sleep(idle_timeout);
+	tokio::pin!(idle_timer);
 
 	loop {
 		tokio::select! {
-			Some(_) = servers.next() => {},
+			Some(_) = servers.next() => {
+				idle_timer.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+			},
+			_ = &mut idle_timer, if args.idle_exit_timeout.is_some() && servers.is_empty() => {
+				info!(params.log, "No connections for {}s, exiting", idle_timeout.as_secs());
+				return Ok(0);
+			},
 			socket = listener.accept_rw() => {
 				match socket {
-					Ok((read, write)) => servers.push(serve_stream(read, write, params.clone())),
+					Ok((read, write, client_addr)) => servers.push(serve_stream(
+						read,
+						write,
+						ServeStreamParams { client_addr, ..params.clone() },
+					)),
 					Err(e) => {
 						error!(params.log, &format!("Error accepting connection: {}", e));
 						return Ok(1);
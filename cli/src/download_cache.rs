@@ -51,6 +51,13 @@ impl DownloadCache {
 		Some(p)
 	}
 
+	/// Gets the names of the items in the cache, most recently used first.
+	pub fn get(&self) -> Vec<String> {
+		let mut names = self.state.load();
+		names.retain(|n| self.path.join(n).exists());
+		names
+	}
+
 	/// Removes the item from the cache, if it exists
 	pub fn delete(&self, name: &str) -> Result<(), WrappedError> {
 		let f = self.path.join(name);
@@ -86,7 +93,10 @@ impl DownloadCache {
 		let _ = remove_dir_all(&temp_dir).await; // cleanup any existing
 
 		create_dir_all(&temp_dir).map_err(|e| wrap(e, "error creating server directory"))?;
-		do_create(temp_dir.clone()).await?;
+		if let Err(e) = do_create(temp_dir.clone()).await {
+			let _ = remove_dir_all(&temp_dir).await;
+			return Err(e);
+		}
 
 		let _ = self.touch(name.to_string());
 		std::fs::rename(&temp_dir, &target_dir)
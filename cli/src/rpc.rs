@@ -134,7 +134,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 						serial.serialize(ErrorResponse {
 							id,
 							error: ResponseError {
-								code: -1,
+								code: err.rpc_code(),
 								message: format!("{:?}", err),
 							},
 						})
@@ -185,7 +185,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 							serial.serialize(ErrorResponse {
 								id,
 								error: ResponseError {
-									code: -1,
+									code: err.rpc_code(),
 									message: format!("{:?}", err),
 								},
 							})
@@ -258,7 +258,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 							serial.serialize(ErrorResponse {
 								id,
 								error: ResponseError {
-									code: -1,
+									code: err.rpc_code(),
 									message: format!("{:?}", err),
 								},
 							})
@@ -12,7 +12,6 @@ use crate::{
 	update_service::{unzip_downloaded_release, Platform, Release, TargetKind, UpdateService},
 	util::{
 		errors::{wrap, AnyError, CodeError, CorruptDownload},
-		http,
 		io::{ReportCopyProgress, SilentCopyProgress},
 	},
 };
@@ -67,9 +66,10 @@ impl<'a> SelfUpdate<'a> {
 	) -> Result<(), AnyError> {
 		// 1. Download the archive into a temporary directory
 		let tempdir = tempdir().map_err(|e| wrap(e, "Failed to create temp dir"))?;
-		let stream = self.update_service.get_download_stream(release).await?;
-		let archive_path = tempdir.path().join(stream.url_path_basename().unwrap());
-		http::download_into_file(&archive_path, progress, stream).await?;
+		let archive_path = self
+			.update_service
+			.download_release(release, tempdir.path(), progress)
+			.await?;
 
 		// 2. Unzip the archive and get the binary
 		let target_path =
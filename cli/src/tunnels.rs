@@ -16,6 +16,8 @@ pub mod local_forwarding;
 mod wsl_detect;
 mod challenge;
 mod control_server;
+mod fs_rpc;
+mod fs_watch;
 mod nosleep;
 #[cfg(target_os = "linux")]
 mod nosleep_linux;
@@ -24,6 +26,8 @@ mod nosleep_macos;
 #[cfg(target_os = "windows")]
 mod nosleep_windows;
 mod port_forwarder;
+mod process_rpc;
+mod pty;
 mod server_bridge;
 mod server_multiplexer;
 mod service;
@@ -34,6 +38,7 @@ mod service_macos;
 #[cfg(target_os = "windows")]
 mod service_windows;
 mod socket_signal;
+mod system_info;
 
 pub use control_server::{serve, serve_stream, Next, ServeStreamParams, AuthRequired};
 pub use nosleep::SleepInhibitor;
@@ -16,7 +16,7 @@ use crate::update_service::{
 };
 use crate::util::command::{capture_command, capture_command_and_check_status, kill_tree};
 use crate::util::errors::{wrap, AnyError, CodeError, ExtensionInstallFailed, WrappedError};
-use crate::util::http::{self, BoxedHttp};
+use crate::util::http::BoxedHttp;
 use crate::util::io::SilentCopyProgress;
 use crate::util::machine::process_exists;
 use crate::{debug, info, log, spanf, trace, warning};
@@ -397,24 +397,20 @@ impl<'a> ServerBuilder<'a> {
 				let tmpdir =
 					tempfile::tempdir().map_err(|e| wrap(e, "error creating temp download dir"))?;
 
-				let response = update_service
-					.get_download_stream(&self.server_params.release)
-					.await?;
-				let archive_path = tmpdir.path().join(response.url_path_basename().unwrap());
-
 				info!(
 					self.logger,
 					"Downloading {} server -> {}",
 					QUALITYLESS_PRODUCT_NAME,
-					archive_path.display()
+					tmpdir.path().display()
 				);
 
-				http::download_into_file(
-					&archive_path,
-					self.logger.get_download_logger("server download progress:"),
-					response,
-				)
-				.await?;
+				let archive_path = update_service
+					.download_release(
+						&self.server_params.release,
+						tmpdir.path(),
+						self.logger.get_download_logger("server download progress:"),
+					)
+					.await?;
 
 				let server_dir = target_dir.join(SERVER_FOLDER_NAME);
 				unzip_downloaded_release(&archive_path, &server_dir, SilentCopyProgress())?;
@@ -806,11 +802,9 @@ pub async fn download_cli_into_cache(
 		.create(&cache_name, |target_dir| async move {
 			let tmpdir =
 				tempfile::tempdir().map_err(|e| wrap(e, "error creating temp download dir"))?;
-			let response = update_service.get_download_stream(release).await?;
-
-			let name = response.url_path_basename().unwrap();
-			let archive_path = tmpdir.path().join(name);
-			http::download_into_file(&archive_path, SilentCopyProgress(), response).await?;
+			let archive_path = update_service
+				.download_release(release, tmpdir.path(), SilentCopyProgress())
+				.await?;
 			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
 			Ok(())
 		})
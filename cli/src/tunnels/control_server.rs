@@ -12,6 +12,7 @@ use crate::state::LauncherPaths;
 use crate::tunnels::protocol::{HttpRequestParams, METHOD_CHALLENGE_ISSUE};
 use crate::tunnels::socket_signal::CloseReason;
 use crate::update_service::{Platform, Release, TargetKind, UpdateService};
+use crate::util::auth_lockout::AuthLockout;
 use crate::util::errors::{
 	wrap, AnyError, CodeError, MismatchedLaunchModeError, NoAttachedServerError,
 };
@@ -28,6 +29,7 @@ use futures::FutureExt;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::KeyValue;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::process::Stdio;
 use tokio::pin;
 use tokio::process::{ChildStderr, ChildStdin};
@@ -45,22 +47,29 @@ use super::code_server::{
 	SocketCodeServer,
 };
 use super::dev_tunnels::ActiveTunnel;
+use super::fs_rpc;
+use super::fs_watch;
 use super::paths::prune_stopped_servers;
 use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
+use super::process_rpc;
 use super::protocol::{
 	AcquireCliParams, CallServerHttpParams, CallServerHttpResult, ChallengeIssueParams,
 	ChallengeIssueResponse, ChallengeVerifyParams, ClientRequestMethod, EmptyObject, ForwardParams,
-	ForwardResult, FsStatRequest, FsStatResponse, GetEnvResponse, GetHostnameResponse,
-	HttpBodyParams, HttpHeadersParams, ServeParams, ServerLog, ServerMessageParams, SpawnParams,
+	ForwardResult, FsMkdirParams, FsReadParams, FsReaddirParams, FsRealpathParams, FsRenameParams,
+	FsRmParams, FsStatRequest, FsStatResponse, FsWatchParams, FsWriteParams, GetEnvResponse,
+	GetHostnameResponse, HttpBodyParams, HttpHeadersParams, ProcessKillTreeParams,
+	ProcessSignalParams, ServeParams, ServerLog, ServerMessageParams, SpawnParams, SpawnPtyParams,
 	SpawnResult, ToClientRequest, UnforwardParams, UpdateParams, UpdateResult, VersionResponse,
 	METHOD_CHALLENGE_VERIFY,
 };
+use super::pty;
 use super::server_bridge::ServerBridge;
 use super::server_multiplexer::ServerMultiplexer;
 use super::shutdown_signal::ShutdownSignal;
 use super::socket_signal::{
 	ClientMessageDecoder, ServerMessageDestination, ServerMessageSink, SocketSignal,
 };
+use super::system_info;
 
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
 type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
@@ -90,6 +99,10 @@ struct HandlerContext {
 	http: Arc<FallbackSimpleHttp>,
 	/// requests being served by the client
 	http_requests: HttpRequestsMap,
+	/// Locks out clients that give a bad token too often
+	auth_lockout: Option<Arc<AuthLockout>>,
+	/// Address of the client, when connected over TCP
+	client_addr: Option<IpAddr>,
 }
 
 /// Handler auth state.
@@ -216,6 +229,8 @@ pub async fn serve(
 						platform,
 						exit_barrier: own_exit,
 						requires_auth: AuthRequired::None,
+						auth_lockout: None,
+						client_addr: None,
 					}).with_context(cx.clone()).await;
 
 					cx.span().add_event(
@@ -248,6 +263,10 @@ pub struct ServeStreamParams {
 	pub platform: Platform,
 	pub requires_auth: AuthRequired,
 	pub exit_barrier: Barrier<ShutdownSignal>,
+	/// Locks out clients that give a bad token too often
+	pub auth_lockout: Option<Arc<AuthLockout>>,
+	/// Address of the client, when connected over TCP
+	pub client_addr: Option<IpAddr>,
 }
 
 pub async fn serve_stream(
@@ -278,6 +297,8 @@ fn make_socket_rpc(
 	port_forwarding: Option<PortForwarding>,
 	requires_auth: AuthRequired,
 	platform: Platform,
+	auth_lockout: Option<Arc<AuthLockout>>,
+	client_addr: Option<IpAddr>,
 ) -> RpcDispatcher<MsgPackSerializer, HandlerContext> {
 	let http_requests = Arc::new(std::sync::Mutex::new(HashMap::new()));
 	let server_bridges = ServerMultiplexer::new();
@@ -301,6 +322,8 @@ fn make_socket_rpc(
 			http_delegated,
 		)),
 		http_requests,
+		auth_lockout,
+		client_addr,
 	});
 
 	rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
@@ -309,12 +332,71 @@ fn make_socket_rpc(
 		ensure_auth(&c.auth_state)?;
 		handle_stat(p.path)
 	});
+	rpc.register_async("fs_readdir", |p: FsReaddirParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_readdir(p).await
+	});
+	rpc.register_duplex("fs_read", 1, |mut streams, p: FsReadParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_read(streams.remove(0), p).await
+	});
+	rpc.register_duplex(
+		"fs_write",
+		1,
+		|mut streams, p: FsWriteParams, c| async move {
+			ensure_auth(&c.auth_state)?;
+			fs_rpc::handle_write(streams.remove(0), p).await
+		},
+	);
+	rpc.register_duplex(
+		"fs_watch",
+		1,
+		|mut streams, p: FsWatchParams, c| async move {
+			ensure_auth(&c.auth_state)?;
+			fs_watch::handle_watch(&c.log, streams.remove(0), p).await
+		},
+	);
+	rpc.register_async("fs_mkdir", |p: FsMkdirParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_mkdir(p).await
+	});
+	rpc.register_async("fs_rename", |p: FsRenameParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_rename(p).await
+	});
+	rpc.register_async("fs_rm", |p: FsRmParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_rm(p).await
+	});
+	rpc.register_async("fs_realpath", |p: FsRealpathParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		fs_rpc::handle_realpath(p).await
+	});
+	rpc.register_async("process_list", |_: EmptyObject, c| async move {
+		ensure_auth(&c.auth_state)?;
+		process_rpc::handle_list().await
+	});
+	rpc.register_async("process_signal", |p: ProcessSignalParams, c| async move {
+		ensure_auth(&c.auth_state)?;
+		process_rpc::handle_signal(p).await
+	});
+	rpc.register_async(
+		"process_kill_tree",
+		|p: ProcessKillTreeParams, c| async move {
+			ensure_auth(&c.auth_state)?;
+			process_rpc::handle_kill_tree(p).await
+		},
+	);
 	rpc.register_sync("get_env", |_: EmptyObject, c| {
 		ensure_auth(&c.auth_state)?;
 		handle_get_env()
 	});
+	rpc.register_async("get_system_info", |_: EmptyObject, c| async move {
+		ensure_auth(&c.auth_state)?;
+		system_info::handle_get_system_info(&c.launcher_paths).await
+	});
 	rpc.register_sync(METHOD_CHALLENGE_ISSUE, |p: ChallengeIssueParams, c| {
-		handle_challenge_issue(p, &c.auth_state)
+		handle_challenge_issue(p, &c.auth_state, &c.auth_lockout, c.client_addr)
 	});
 	rpc.register_sync(METHOD_CHALLENGE_VERIFY, |p: ChallengeVerifyParams, c| {
 		handle_challenge_verify(p.response, &c.auth_state)
@@ -360,6 +442,14 @@ fn make_socket_rpc(
 		)
 		.await
 	});
+	rpc.register_duplex(
+		"spawn_pty",
+		2,
+		|mut streams, p: SpawnPtyParams, c| async move {
+			ensure_auth(&c.auth_state)?;
+			pty::handle_spawn_pty(&c.log, streams.remove(0), streams.remove(0), p).await
+		},
+	);
 	rpc.register_duplex(
 		"spawn_cli",
 		3,
@@ -424,6 +514,8 @@ async fn process_socket(
 		code_server_args,
 		platform,
 		requires_auth,
+		auth_lockout,
+		client_addr,
 	} = params;
 
 	let (http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log.clone());
@@ -441,6 +533,8 @@ async fn process_socket(
 		port_forwarding,
 		requires_auth,
 		platform,
+		auth_lockout,
+		client_addr,
 	);
 
 	{
@@ -811,12 +905,7 @@ fn handle_stat(path: String) -> Result<FsStatResponse, AnyError> {
 		.map(|m| FsStatResponse {
 			exists: true,
 			size: Some(m.len()),
-			kind: Some(match m.file_type() {
-				t if t.is_dir() => "dir",
-				t if t.is_file() => "file",
-				t if t.is_symlink() => "link",
-				_ => "unknown",
-			}),
+			kind: Some(fs_rpc::file_kind(m.file_type())),
 		})
 		.unwrap_or_default())
 }
@@ -837,16 +926,30 @@ fn handle_get_env() -> Result<GetEnvResponse, AnyError> {
 fn handle_challenge_issue(
 	params: ChallengeIssueParams,
 	auth_state: &Arc<std::sync::Mutex<AuthState>>,
+	auth_lockout: &Option<Arc<AuthLockout>>,
+	client_addr: Option<IpAddr>,
 ) -> Result<ChallengeIssueResponse, AnyError> {
 	let challenge = create_challenge();
 
 	let mut auth_state = auth_state.lock().unwrap();
 	if let AuthState::WaitingForChallenge(Some(s)) = &*auth_state {
-		println!("looking for token {}, got {:?}", s, params.token);
-		match &params.token {
-			Some(t) if s != t => return Err(CodeError::AuthChallengeBadToken.into()),
-			None => return Err(CodeError::AuthChallengeBadToken.into()),
-			_ => {}
+		if let Some(remaining) = auth_lockout
+			.as_ref()
+			.and_then(|l| l.locked_out_for(client_addr))
+		{
+			return Err(CodeError::AuthLockedOut(remaining.as_secs().max(1)).into());
+		}
+
+		let valid = matches!(&params.token, Some(t) if s == t);
+		if let Some(l) = auth_lockout {
+			if valid {
+				l.record_success(client_addr);
+			} else {
+				l.record_failure(client_addr);
+			}
+		}
+		if !valid {
+			return Err(CodeError::AuthChallengeBadToken.into());
 		}
 	}
 
@@ -1185,3 +1288,211 @@ async fn do_challenge_response_flow(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use serde::de::DeserializeOwned;
+	use serde::{Deserialize, Serialize};
+	use tokio::io::{ReadHalf, WriteHalf};
+
+	use super::*;
+	use crate::rpc::{FullRequest, ResponseError};
+	use crate::tunnels::protocol::{FsReadResponse, FsWriteResponse};
+
+	#[derive(Deserialize)]
+	struct Incoming {
+		id: Option<u32>,
+		method: Option<String>,
+	}
+
+	#[derive(Deserialize)]
+	struct Response<R> {
+		result: Option<R>,
+		error: Option<ResponseError>,
+	}
+
+	#[derive(Deserialize)]
+	struct Notification<P> {
+		params: P,
+	}
+
+	#[derive(Deserialize)]
+	struct StreamsStarted {
+		for_request_id: u32,
+		stream_ids: Vec<u32>,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct StreamData {
+		#[serde(with = "serde_bytes")]
+		segment: Vec<u8>,
+		stream: u32,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct StreamEnded {
+		stream: u32,
+	}
+
+	/// Client for a control server started over an in-memory stream.
+	struct TestClient {
+		read: ReadHalf<DuplexStream>,
+		write: WriteHalf<DuplexStream>,
+		buf: bytes::BytesMut,
+		codec: MsgPackCodec<Incoming>,
+		next_id: u32,
+		_dir: tempfile::TempDir,
+		_exit: BarrierOpener<ShutdownSignal>,
+	}
+
+	impl TestClient {
+		fn new() -> Self {
+			let dir = tempfile::tempdir().unwrap();
+			let (exit_barrier, exit) = new_barrier();
+			let (client, server) = tokio::io::duplex(1 << 16);
+			let (server_read, server_write) = tokio::io::split(server);
+			let params = ServeStreamParams {
+				log: log::Logger::test(),
+				launcher_paths: LauncherPaths::new_without_replacements(dir.path().to_owned()),
+				code_server_args: CodeServerArgs::default(),
+				platform: Platform::LinuxX64,
+				requires_auth: AuthRequired::None,
+				exit_barrier,
+				auth_lockout: None,
+				client_addr: None,
+			};
+			tokio::spawn(serve_stream(server_read, server_write, params));
+
+			let (read, write) = tokio::io::split(client);
+			Self {
+				read,
+				write,
+				buf: bytes::BytesMut::new(),
+				codec: MsgPackCodec::new(),
+				next_id: 1,
+				_dir: dir,
+				_exit: exit,
+			}
+		}
+
+		async fn send(&mut self, id: Option<u32>, method: &str, params: impl Serialize) {
+			let msg = rmp_serde::to_vec_named(&FullRequest { id, method, params }).unwrap();
+			self.write.write_all(&msg).await.unwrap();
+		}
+
+		async fn request(&mut self, method: &str, params: impl Serialize) -> u32 {
+			let id = self.next_id;
+			self.next_id += 1;
+			self.send(Some(id), method, params).await;
+			id
+		}
+
+		async fn next_message(&mut self) -> (Incoming, Vec<u8>) {
+			loop {
+				if let Some(m) = self.codec.decode(&mut self.buf).unwrap() {
+					return (m.obj, m.vec);
+				}
+				assert!(self.read.read_buf(&mut self.buf).await.unwrap() > 0);
+			}
+		}
+
+		/// Calls a method with a single stream, sending `input` on it, and
+		/// returns the method's result along with what it sent on the stream.
+		async fn call_stream<R: DeserializeOwned>(
+			&mut self,
+			method: &str,
+			params: impl Serialize,
+			input: &[u8],
+		) -> (Result<R, ResponseError>, Vec<u8>) {
+			let id = self.request(method, params).await;
+			let mut stream = None;
+			let mut output = vec![];
+			let mut stream_ended = false;
+			let mut result = None;
+			while result.is_none() || (stream.is_some() && !stream_ended) {
+				let (m, body) = self.next_message().await;
+				match m.method.as_deref() {
+					Some("streams_started") => {
+						let n: Notification<StreamsStarted> = rmp_serde::from_slice(&body).unwrap();
+						if n.params.for_request_id != id {
+							continue;
+						}
+						let s = n.params.stream_ids[0];
+						stream = Some(s);
+						if !input.is_empty() {
+							let data = StreamData {
+								segment: input.to_vec(),
+								stream: s,
+							};
+							self.send(None, "stream_data", data).await;
+						}
+						self.send(None, "stream_ended", StreamEnded { stream: s })
+							.await;
+					}
+					Some("stream_data") => {
+						let n: Notification<StreamData> = rmp_serde::from_slice(&body).unwrap();
+						if Some(n.params.stream) == stream {
+							output.extend(n.params.segment);
+						}
+					}
+					Some("stream_ended") => {
+						let n: Notification<StreamEnded> = rmp_serde::from_slice(&body).unwrap();
+						stream_ended |= Some(n.params.stream) == stream;
+					}
+					Some(_) => {}
+					None if m.id == Some(id) => result = Some(into_result(&body)),
+					None => {}
+				}
+			}
+
+			(result.unwrap(), output)
+		}
+	}
+
+	fn into_result<R: DeserializeOwned>(body: &[u8]) -> Result<R, ResponseError> {
+		let r: Response<R> = rmp_serde::from_slice(body).unwrap();
+		match r.error {
+			Some(e) => Err(e),
+			None => Ok(r.result.unwrap()),
+		}
+	}
+
+	fn path_str(p: &std::path::Path) -> String {
+		p.to_string_lossy().to_string()
+	}
+
+	/// Round-trips a file through `fs_write` and `fs_read`, which covers
+	/// requests, responses, and streams in both directions.
+	#[tokio::test]
+	async fn test_rpc_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = path_str(&dir.path().join("a.txt"));
+		let mut client = TestClient::new();
+
+		let (r, _) = client
+			.call_stream::<FsWriteResponse>(
+				"fs_write",
+				FsWriteParams {
+					path: file.clone(),
+					offset: None,
+				},
+				b"hello world",
+			)
+			.await;
+		assert_eq!(r.unwrap().bytes_written, 11);
+
+		let (r, data) = client
+			.call_stream::<FsReadResponse>(
+				"fs_read",
+				FsReadParams {
+					path: file,
+					offset: 0,
+					length: None,
+				},
+				b"",
+			)
+			.await;
+		assert_eq!(r.unwrap().bytes_read, 11);
+		assert_eq!(data, b"hello world");
+	}
+}
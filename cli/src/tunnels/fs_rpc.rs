@@ -0,0 +1,307 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Handlers for the `fs_*` methods of the control server, which let clients
+//! work with files before a code server is running.
+
+use std::fs::FileType;
+use std::io::{self, SeekFrom};
+
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, DuplexStream};
+
+use crate::util::errors::{AnyError, CodeError};
+
+use super::protocol::{
+	EmptyObject, FsDirEntry, FsErrorKind, FsMkdirParams, FsReadParams, FsReadResponse,
+	FsReaddirParams, FsReaddirResponse, FsRealpathParams, FsRealpathResponse, FsRenameParams,
+	FsRmParams, FsWriteParams, FsWriteResponse,
+};
+
+/// Gets the type of a file as it's reported to clients.
+pub fn file_kind(t: FileType) -> &'static str {
+	match t {
+		t if t.is_dir() => "dir",
+		t if t.is_file() => "file",
+		t if t.is_symlink() => "link",
+		_ => "unknown",
+	}
+}
+
+pub async fn handle_readdir(params: FsReaddirParams) -> Result<FsReaddirResponse, AnyError> {
+	let path = &params.path;
+	let mut dir = fs::read_dir(path).await.map_err(|e| fs_error(e, path))?;
+
+	let mut entries = vec![];
+	while let Some(entry) = dir.next_entry().await.map_err(|e| fs_error(e, path))? {
+		// the entry may have been removed since it was listed
+		let kind = match entry.file_type().await {
+			Ok(t) => file_kind(t),
+			Err(_) => continue,
+		};
+		entries.push(FsDirEntry {
+			name: entry.file_name().to_string_lossy().to_string(),
+			kind: kind.to_string(),
+		});
+	}
+
+	Ok(FsReaddirResponse { entries })
+}
+
+/// Writes the requested range of the file to the stream, closing it once done.
+pub async fn handle_read(
+	mut stream: DuplexStream,
+	params: FsReadParams,
+) -> Result<FsReadResponse, AnyError> {
+	let path = &params.path;
+	let mut file = fs::File::open(path).await.map_err(|e| fs_error(e, path))?;
+	let size = file.metadata().await.map_err(|e| fs_error(e, path))?.len();
+	if params.offset > 0 {
+		file.seek(SeekFrom::Start(params.offset))
+			.await
+			.map_err(|e| fs_error(e, path))?;
+	}
+
+	let mut range = file.take(params.length.unwrap_or(u64::MAX));
+	let bytes_read = tokio::io::copy(&mut range, &mut stream)
+		.await
+		.map_err(|e| fs_error(e, path))?;
+	let _ = stream.shutdown().await;
+
+	Ok(FsReadResponse { bytes_read, size })
+}
+
+/// Writes the contents of the stream to the file until the client ends it.
+pub async fn handle_write(
+	mut stream: DuplexStream,
+	params: FsWriteParams,
+) -> Result<FsWriteResponse, AnyError> {
+	let path = &params.path;
+	let mut file = fs::OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(params.offset.is_none())
+		.open(path)
+		.await
+		.map_err(|e| fs_error(e, path))?;
+	if let Some(offset) = params.offset {
+		file.seek(SeekFrom::Start(offset))
+			.await
+			.map_err(|e| fs_error(e, path))?;
+	}
+
+	let bytes_written = tokio::io::copy(&mut stream, &mut file)
+		.await
+		.map_err(|e| fs_error(e, path))?;
+	file.flush().await.map_err(|e| fs_error(e, path))?;
+
+	Ok(FsWriteResponse { bytes_written })
+}
+
+pub async fn handle_mkdir(params: FsMkdirParams) -> Result<EmptyObject, AnyError> {
+	let path = &params.path;
+	if params.recursive {
+		fs::create_dir_all(path).await
+	} else {
+		fs::create_dir(path).await
+	}
+	.map_err(|e| fs_error(e, path))?;
+
+	Ok(EmptyObject {})
+}
+
+pub async fn handle_rename(params: FsRenameParams) -> Result<EmptyObject, AnyError> {
+	if !params.overwrite && fs::symlink_metadata(&params.to).await.is_ok() {
+		return Err(CodeError::FsError {
+			kind: FsErrorKind::AlreadyExists,
+			path: params.to,
+			message: "destination already exists".to_string(),
+		}
+		.into());
+	}
+
+	fs::rename(&params.from, &params.to)
+		.await
+		.map_err(|e| fs_error(e, &params.from))?;
+
+	Ok(EmptyObject {})
+}
+
+pub async fn handle_rm(params: FsRmParams) -> Result<EmptyObject, AnyError> {
+	let path = &params.path;
+	let metadata = fs::symlink_metadata(path)
+		.await
+		.map_err(|e| fs_error(e, path))?;
+
+	if !metadata.is_dir() {
+		fs::remove_file(path).await
+	} else if params.recursive {
+		fs::remove_dir_all(path).await
+	} else {
+		fs::remove_dir(path).await
+	}
+	.map_err(|e| fs_error(e, path))?;
+
+	Ok(EmptyObject {})
+}
+
+pub async fn handle_realpath(params: FsRealpathParams) -> Result<FsRealpathResponse, AnyError> {
+	let path = fs::canonicalize(&params.path)
+		.await
+		.map_err(|e| fs_error(e, &params.path))?;
+
+	Ok(FsRealpathResponse {
+		path: path.to_string_lossy().to_string(),
+	})
+}
+
+pub fn fs_error(e: io::Error, path: &str) -> AnyError {
+	CodeError::FsError {
+		kind: error_kind(&e),
+		path: path.to_string(),
+		message: e.to_string(),
+	}
+	.into()
+}
+
+fn error_kind(e: &io::Error) -> FsErrorKind {
+	match e.kind() {
+		io::ErrorKind::NotFound => FsErrorKind::NotFound,
+		io::ErrorKind::PermissionDenied => FsErrorKind::PermissionDenied,
+		io::ErrorKind::AlreadyExists => FsErrorKind::AlreadyExists,
+		io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => FsErrorKind::InvalidInput,
+		_ => match e.raw_os_error() {
+			#[cfg(unix)]
+			Some(libc::ENOTDIR) => FsErrorKind::NotADirectory,
+			#[cfg(unix)]
+			Some(libc::EISDIR) => FsErrorKind::IsADirectory,
+			#[cfg(unix)]
+			Some(libc::ENOTEMPTY) => FsErrorKind::DirectoryNotEmpty,
+			// ERROR_DIRECTORY
+			#[cfg(windows)]
+			Some(267) => FsErrorKind::NotADirectory,
+			// ERROR_DIR_NOT_EMPTY
+			#[cfg(windows)]
+			Some(145) => FsErrorKind::DirectoryNotEmpty,
+			_ => FsErrorKind::Other,
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::*;
+
+	fn path_str(p: &Path) -> String {
+		p.to_string_lossy().to_string()
+	}
+
+	async fn write(path: &str, offset: Option<u64>, data: &[u8]) -> FsWriteResponse {
+		let (mut client, stream) = tokio::io::duplex(1 << 16);
+		client.write_all(data).await.unwrap();
+		drop(client);
+		let params = FsWriteParams {
+			path: path.to_string(),
+			offset,
+		};
+		handle_write(stream, params).await.unwrap()
+	}
+
+	async fn read(
+		path: &str,
+		offset: u64,
+		length: Option<u64>,
+	) -> Result<(FsReadResponse, Vec<u8>), AnyError> {
+		let (mut client, stream) = tokio::io::duplex(1 << 16);
+		let params = FsReadParams {
+			path: path.to_string(),
+			offset,
+			length,
+		};
+		let r = handle_read(stream, params).await?;
+		let mut data = vec![];
+		client.read_to_end(&mut data).await.unwrap();
+		Ok((r, data))
+	}
+
+	#[tokio::test]
+	async fn test_read_write() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = path_str(&dir.path().join("a.txt"));
+
+		assert_eq!(write(&file, None, b"hello world").await.bytes_written, 11);
+		assert_eq!(write(&file, Some(6), b"there").await.bytes_written, 5);
+
+		let (r, data) = read(&file, 0, None).await.unwrap();
+		assert_eq!((r.bytes_read, r.size), (11, 11));
+		assert_eq!(data, b"hello there");
+
+		let (r, data) = read(&file, 2, Some(3)).await.unwrap();
+		assert_eq!(r.bytes_read, 3);
+		assert_eq!(data, b"llo");
+
+		// responses aren't Debug, so unwrap_err() can't be used
+		let err = read(&path_str(&dir.path().join("missing")), 0, None)
+			.await
+			.err()
+			.unwrap();
+		assert_eq!(err.rpc_code(), FsErrorKind::NotFound as i32);
+	}
+
+	#[tokio::test]
+	async fn test_directories() {
+		let dir = tempfile::tempdir().unwrap();
+		let a = dir.path().join("a");
+		let nested = a.join("b");
+
+		let mkdir = |recursive| FsMkdirParams {
+			path: path_str(&nested),
+			recursive,
+		};
+		let err = handle_mkdir(mkdir(false)).await.unwrap_err();
+		assert_eq!(err.rpc_code(), FsErrorKind::NotFound as i32);
+		handle_mkdir(mkdir(true)).await.unwrap();
+		std::fs::write(a.join("f.txt"), "x").unwrap();
+
+		let mut entries = handle_readdir(FsReaddirParams { path: path_str(&a) })
+			.await
+			.unwrap()
+			.entries;
+		entries.sort_by(|a, b| a.name.cmp(&b.name));
+		let entries: Vec<_> = entries.iter().map(|e| (&*e.name, &*e.kind)).collect();
+		assert_eq!(entries, vec![("b", "dir"), ("f.txt", "file")]);
+
+		let realpath = handle_realpath(FsRealpathParams {
+			path: path_str(&nested.join("..").join("f.txt")),
+		})
+		.await
+		.unwrap();
+		assert_eq!(
+			realpath.path,
+			path_str(&a.join("f.txt").canonicalize().unwrap())
+		);
+
+		let rename = |to: &str, overwrite| FsRenameParams {
+			from: path_str(&a.join("f.txt")),
+			to: path_str(&a.join(to)),
+			overwrite,
+		};
+		let err = handle_rename(rename("b", false)).await.unwrap_err();
+		assert_eq!(err.rpc_code(), FsErrorKind::AlreadyExists as i32);
+		handle_rename(rename("g.txt", false)).await.unwrap();
+		assert!(a.join("g.txt").exists());
+
+		let rm = |recursive| FsRmParams {
+			path: path_str(&a),
+			recursive,
+		};
+		let err = handle_rm(rm(false)).await.unwrap_err();
+		assert_eq!(err.rpc_code(), FsErrorKind::DirectoryNotEmpty as i32);
+		handle_rm(rm(true)).await.unwrap();
+		assert!(!a.exists());
+	}
+}
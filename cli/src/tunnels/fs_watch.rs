@@ -0,0 +1,562 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Handler for the `fs_watch` method of the control server, which streams
+//! changes to a file or directory. Uses inotify on Linux, and otherwise polls.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::log;
+use crate::util::errors::AnyError;
+
+use super::fs_rpc::fs_error;
+use super::protocol::{EmptyObject, FsWatchEvent, FsWatchEventKind, FsWatchParams};
+
+/// How often the polling watcher checks for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes changes to the path to the stream until the client closes it, or
+/// the path is deleted.
+pub async fn handle_watch(
+	log: &log::Logger,
+	stream: DuplexStream,
+	params: FsWatchParams,
+) -> Result<EmptyObject, AnyError> {
+	let root = PathBuf::from(&params.path);
+	let mut watcher = Watcher::new(log, &root, params.recursive)
+		.await
+		.map_err(|e| fs_error(e, &params.path))?;
+
+	let (mut read, mut write) = tokio::io::split(stream);
+	let mut buf = [0u8; 64];
+	loop {
+		tokio::select! {
+			// the client doesn't send anything, but reads end once it closes the stream
+			r = read.read(&mut buf) => match r {
+				Ok(0) | Err(_) => break,
+				Ok(_) => continue,
+			},
+			events = watcher.next() => {
+				let events = events.map_err(|e| fs_error(e, &params.path))?;
+				let mut root_deleted = false;
+				for event in events {
+					root_deleted |= event.kind == FsWatchEventKind::Delete
+						&& Path::new(&event.path) == root;
+					let msg = rmp_serde::to_vec_named(&event).unwrap();
+					if write.write_all(&msg).await.is_err() {
+						return Ok(EmptyObject {});
+					}
+				}
+				if root_deleted {
+					break;
+				}
+			}
+		}
+	}
+
+	let _ = write.shutdown().await;
+	Ok(EmptyObject {})
+}
+
+enum Watcher {
+	#[cfg(target_os = "linux")]
+	Inotify(inotify::InotifyWatcher),
+	Poll(PollWatcher),
+}
+
+impl Watcher {
+	async fn new(log: &log::Logger, root: &Path, recursive: bool) -> io::Result<Self> {
+		// fail early with a NotFound error, rather than polling for a missing path
+		tokio::fs::symlink_metadata(root).await?;
+
+		#[cfg(target_os = "linux")]
+		match inotify::InotifyWatcher::new(root, recursive).await {
+			Ok(w) => return Ok(Watcher::Inotify(w)),
+			Err(e) => warning!(
+				log,
+				"Could not watch {} with inotify, polling instead: {}",
+				root.display(),
+				e
+			),
+		}
+		#[cfg(not(target_os = "linux"))]
+		let _ = log;
+
+		Ok(Watcher::Poll(PollWatcher::new(root, recursive).await))
+	}
+
+	/// Waits for the next batch of changes.
+	async fn next(&mut self) -> io::Result<Vec<FsWatchEvent>> {
+		match self {
+			#[cfg(target_os = "linux")]
+			Watcher::Inotify(w) => w.next().await,
+			Watcher::Poll(w) => Ok(w.next().await),
+		}
+	}
+}
+
+fn event(kind: FsWatchEventKind, path: &Path) -> FsWatchEvent {
+	FsWatchEvent {
+		kind,
+		path: path.to_string_lossy().to_string(),
+	}
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct PolledEntry {
+	is_dir: bool,
+	len: u64,
+	modified: Option<SystemTime>,
+}
+
+type Snapshot = HashMap<PathBuf, PolledEntry>;
+
+/// Watcher that periodically lists the path and compares it with the
+/// previous listing.
+struct PollWatcher {
+	root: PathBuf,
+	recursive: bool,
+	entries: Snapshot,
+	interval: tokio::time::Interval,
+}
+
+impl PollWatcher {
+	async fn new(root: &Path, recursive: bool) -> Self {
+		let mut interval = tokio::time::interval(POLL_INTERVAL);
+		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+		interval.tick().await; // the first tick is immediate
+
+		let root = root.to_owned();
+		Self {
+			entries: take_snapshot(root.clone(), recursive).await,
+			root,
+			recursive,
+			interval,
+		}
+	}
+
+	async fn next(&mut self) -> Vec<FsWatchEvent> {
+		loop {
+			self.interval.tick().await;
+			let entries = take_snapshot(self.root.clone(), self.recursive).await;
+			let events = diff_snapshots(&self.entries, &entries);
+			self.entries = entries;
+			if !events.is_empty() {
+				return events;
+			}
+		}
+	}
+}
+
+async fn take_snapshot(root: PathBuf, recursive: bool) -> Snapshot {
+	tokio::task::spawn_blocking(move || {
+		let mut entries = Snapshot::new();
+		if let Ok(m) = std::fs::symlink_metadata(&root) {
+			if m.is_dir() {
+				add_children_to_snapshot(&root, recursive, &mut entries);
+			}
+			entries.insert(root, polled_entry(&m));
+		}
+		entries
+	})
+	.await
+	.unwrap()
+}
+
+fn add_children_to_snapshot(dir: &Path, recursive: bool, entries: &mut Snapshot) {
+	let read_dir = match std::fs::read_dir(dir) {
+		Ok(r) => r,
+		Err(_) => return,
+	};
+
+	for entry in read_dir.flatten() {
+		// entry metadata doesn't follow symlinks
+		let m = match entry.metadata() {
+			Ok(m) => m,
+			Err(_) => continue,
+		};
+		let path = entry.path();
+		if recursive && m.is_dir() {
+			add_children_to_snapshot(&path, recursive, entries);
+		}
+		entries.insert(path, polled_entry(&m));
+	}
+}
+
+fn polled_entry(m: &std::fs::Metadata) -> PolledEntry {
+	PolledEntry {
+		is_dir: m.is_dir(),
+		len: m.len(),
+		modified: m.modified().ok(),
+	}
+}
+
+fn diff_snapshots(before: &Snapshot, after: &Snapshot) -> Vec<FsWatchEvent> {
+	let mut events = vec![];
+	for (path, entry) in after {
+		match before.get(path) {
+			None => events.push(event(FsWatchEventKind::Create, path)),
+			// directories change whenever their children do, which is already reported
+			Some(b) if b != entry && !(b.is_dir && entry.is_dir) => {
+				events.push(event(FsWatchEventKind::Modify, path))
+			}
+			Some(_) => {}
+		}
+	}
+	for path in before.keys() {
+		if !after.contains_key(path) {
+			events.push(event(FsWatchEventKind::Delete, path));
+		}
+	}
+
+	events.sort_by(|a, b| a.path.cmp(&b.path));
+	events
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+	use std::collections::HashMap;
+	use std::ffi::{CString, OsStr};
+	use std::io;
+	use std::os::unix::ffi::OsStrExt;
+	use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+	use std::path::{Path, PathBuf};
+
+	use tokio::io::unix::AsyncFd;
+
+	use super::{event, FsWatchEvent, FsWatchEventKind};
+
+	const WATCH_MASK: u32 = libc::IN_CREATE
+		| libc::IN_MOVED_TO
+		| libc::IN_MODIFY
+		| libc::IN_DELETE
+		| libc::IN_MOVED_FROM
+		| libc::IN_DELETE_SELF
+		| libc::IN_MOVE_SELF;
+
+	const EVENT_HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+	pub struct InotifyWatcher {
+		fd: AsyncFd<OwnedFd>,
+		root: PathBuf,
+		recursive: bool,
+		/// Paths of the watch descriptors
+		watches: HashMap<i32, PathBuf>,
+		buf: Vec<u8>,
+	}
+
+	impl InotifyWatcher {
+		pub async fn new(root: &Path, recursive: bool) -> io::Result<Self> {
+			let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+			if fd < 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			let mut watcher = Self {
+				fd: AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) })?,
+				root: root.to_owned(),
+				recursive,
+				watches: HashMap::new(),
+				buf: vec![0; 64 * 1024],
+			};
+
+			watcher.add_watch(root)?;
+			if recursive {
+				let root = root.to_owned();
+				let dirs = tokio::task::spawn_blocking(move || {
+					let mut dirs = vec![];
+					list_subdirectories(&root, &mut dirs);
+					dirs
+				})
+				.await
+				.unwrap();
+				for dir in dirs {
+					match watcher.add_watch(&dir) {
+						// removed since it was listed
+						Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+						r => r?,
+					}
+				}
+			}
+
+			Ok(watcher)
+		}
+
+		fn add_watch(&mut self, path: &Path) -> io::Result<()> {
+			let c_path = CString::new(path.as_os_str().as_bytes())
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+			let wd = unsafe {
+				libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), WATCH_MASK)
+			};
+			if wd < 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			self.watches.insert(wd, path.to_owned());
+			Ok(())
+		}
+
+		pub async fn next(&mut self) -> io::Result<Vec<FsWatchEvent>> {
+			loop {
+				let n = self.read().await?;
+				let (mut events, new_dirs) = self.parse_events(n);
+				for dir in new_dirs {
+					self.watch_new_directory(dir, &mut events).await;
+				}
+				if !events.is_empty() {
+					return Ok(events);
+				}
+			}
+		}
+
+		async fn read(&mut self) -> io::Result<usize> {
+			loop {
+				let mut guard = self.fd.readable().await?;
+				let buf = &mut self.buf;
+				let r = guard.try_io(|fd| {
+					let n = unsafe {
+						libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len())
+					};
+					if n < 0 {
+						Err(io::Error::last_os_error())
+					} else {
+						Ok(n as usize)
+					}
+				});
+
+				if let Ok(r) = r {
+					return r;
+				}
+			}
+		}
+
+		/// Parses the events read into the buffer. Also returns directories
+		/// that were created, which need to be watched when recursive.
+		fn parse_events(&mut self, len: usize) -> (Vec<FsWatchEvent>, Vec<PathBuf>) {
+			let mut events: Vec<FsWatchEvent> = vec![];
+			let mut new_dirs = vec![];
+			let mut offset = 0;
+			while offset + EVENT_HEADER_SIZE <= len {
+				let header: libc::inotify_event =
+					unsafe { std::ptr::read_unaligned(self.buf[offset..].as_ptr() as *const _) };
+				let name_start = offset + EVENT_HEADER_SIZE;
+				offset = name_start + header.len as usize;
+
+				if header.mask & libc::IN_Q_OVERFLOW != 0 {
+					// events were dropped, let the client know something changed
+					events.push(event(FsWatchEventKind::Modify, &self.root));
+					continue;
+				}
+				if header.mask & libc::IN_IGNORED != 0 {
+					self.watches.remove(&header.wd);
+					continue;
+				}
+				let dir = match self.watches.get(&header.wd) {
+					Some(d) => d.clone(),
+					None => continue,
+				};
+
+				// the name is padded with nul bytes
+				let name = &self.buf[name_start..offset];
+				let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+				let path = if name.is_empty() {
+					dir
+				} else {
+					dir.join(OsStr::from_bytes(name))
+				};
+
+				let is_dir = header.mask & libc::IN_ISDIR != 0;
+				let kind = if header.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+					if is_dir && self.recursive {
+						new_dirs.push(path.clone());
+					}
+					FsWatchEventKind::Create
+				} else if header.mask & libc::IN_MODIFY != 0 && !is_dir {
+					FsWatchEventKind::Modify
+				} else if header.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+					// a directory moved out of the tree keeps its watches, which
+					// would report changes under a path that no longer exists
+					if is_dir && header.mask & libc::IN_MOVED_FROM != 0 {
+						self.remove_watches_under(&path);
+					}
+					FsWatchEventKind::Delete
+				} else if header.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0
+					&& path == self.root
+				{
+					// other directories are reported by their parent
+					FsWatchEventKind::Delete
+				} else {
+					continue;
+				};
+
+				let e = event(kind, &path);
+				if events.last() != Some(&e) {
+					events.push(e);
+				}
+			}
+
+			(events, new_dirs)
+		}
+
+		/// Watches a directory created in a watched directory, and reports
+		/// anything that was created in it before the watch was added.
+		async fn watch_new_directory(&mut self, dir: PathBuf, events: &mut Vec<FsWatchEvent>) {
+			let mut pending = vec![dir];
+			while let Some(dir) = pending.pop() {
+				// each directory is watched before it's listed, so nothing
+				// created in between is missed
+				if self.add_watch(&dir).is_err() {
+					continue;
+				}
+
+				let entries = tokio::task::spawn_blocking(move || list_entries(&dir))
+					.await
+					.unwrap_or_default();
+				for (path, is_dir) in entries {
+					events.push(event(FsWatchEventKind::Create, &path));
+					if is_dir {
+						pending.push(path);
+					}
+				}
+			}
+		}
+
+		/// Stops watching the directory and everything under it.
+		fn remove_watches_under(&mut self, dir: &Path) {
+			let fd = self.fd.as_raw_fd();
+			self.watches.retain(|wd, path| {
+				if !path.starts_with(dir) {
+					return true;
+				}
+
+				unsafe { libc::inotify_rm_watch(fd, *wd) };
+				false
+			});
+		}
+	}
+
+	/// Lists the directory's entries, and whether each is a directory.
+	fn list_entries(dir: &Path) -> Vec<(PathBuf, bool)> {
+		let read_dir = match std::fs::read_dir(dir) {
+			Ok(r) => r,
+			Err(_) => return vec![],
+		};
+
+		read_dir
+			.flatten()
+			.map(|e| (e.path(), e.file_type().map(|t| t.is_dir()).unwrap_or(false)))
+			.collect()
+	}
+
+	fn list_subdirectories(dir: &Path, dirs: &mut Vec<PathBuf>) {
+		let read_dir = match std::fs::read_dir(dir) {
+			Ok(r) => r,
+			Err(_) => return,
+		};
+
+		for entry in read_dir.flatten() {
+			if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+				let path = entry.path();
+				list_subdirectories(&path, dirs);
+				dirs.push(path);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_watcher() {
+		let dir = tempfile::tempdir().unwrap();
+		let sub = dir.path().join("sub");
+		let file = sub.join("a.txt");
+		let mut watcher = Watcher::new(&log::Logger::test(), dir.path(), true)
+			.await
+			.unwrap();
+		let mut events = vec![];
+
+		async fn wait_for(
+			watcher: &mut Watcher,
+			events: &mut Vec<FsWatchEvent>,
+			kind: FsWatchEventKind,
+			path: &Path,
+		) {
+			let e = event(kind, path);
+			while !events.contains(&e) {
+				let next = tokio::time::timeout(Duration::from_secs(5), watcher.next())
+					.await
+					.unwrap_or_else(|_| panic!("missing {:?} in {:?}", e, events));
+				events.extend(next.unwrap());
+			}
+		}
+
+		// new directories are watched once their creation is seen
+		std::fs::create_dir(&sub).unwrap();
+		wait_for(&mut watcher, &mut events, FsWatchEventKind::Create, &sub).await;
+
+		std::fs::write(&file, "x").unwrap();
+		wait_for(&mut watcher, &mut events, FsWatchEventKind::Create, &file).await;
+		std::fs::remove_file(&file).unwrap();
+		wait_for(&mut watcher, &mut events, FsWatchEventKind::Delete, &file).await;
+
+		// changes in a directory moved out of the tree are no longer reported
+		let outside = tempfile::tempdir().unwrap();
+		let moved = outside.path().join("sub");
+		std::fs::rename(&sub, &moved).unwrap();
+		wait_for(&mut watcher, &mut events, FsWatchEventKind::Delete, &sub).await;
+		std::fs::write(moved.join("b.txt"), "x").unwrap();
+		let marker = dir.path().join("marker");
+		std::fs::write(&marker, "x").unwrap();
+		wait_for(&mut watcher, &mut events, FsWatchEventKind::Create, &marker).await;
+		let stale = sub.join("b.txt").to_string_lossy().to_string();
+		assert!(!events.iter().any(|e| e.path == stale), "{:?}", events);
+	}
+
+	#[test]
+	fn test_diff_snapshots() {
+		let entry = |is_dir, len| PolledEntry {
+			is_dir,
+			len,
+			modified: None,
+		};
+		let before: Snapshot = [
+			(PathBuf::from("/d"), entry(true, 1)),
+			(PathBuf::from("/d/changed"), entry(false, 1)),
+			(PathBuf::from("/d/deleted"), entry(false, 1)),
+			(PathBuf::from("/d/same"), entry(false, 1)),
+		]
+		.into_iter()
+		.collect();
+		let after: Snapshot = [
+			(PathBuf::from("/d"), entry(true, 2)),
+			(PathBuf::from("/d/changed"), entry(false, 2)),
+			(PathBuf::from("/d/created"), entry(false, 1)),
+			(PathBuf::from("/d/same"), entry(false, 1)),
+		]
+		.into_iter()
+		.collect();
+
+		let events: Vec<_> = diff_snapshots(&before, &after)
+			.into_iter()
+			.map(|e| (e.kind, e.path))
+			.collect();
+		assert_eq!(
+			events,
+			vec![
+				(FsWatchEventKind::Modify, "/d/changed".to_string()),
+				(FsWatchEventKind::Create, "/d/created".to_string()),
+				(FsWatchEventKind::Delete, "/d/deleted".to_string()),
+			]
+		);
+	}
+}
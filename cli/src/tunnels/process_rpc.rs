@@ -0,0 +1,152 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Handlers for the `process_*` methods of the control server, which let
+//! clients find and stop processes on the machine.
+
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, Signal, System, SystemExt};
+
+use crate::util::command::kill_tree;
+use crate::util::errors::{wrap, AnyError, CodeError};
+
+use super::protocol::{
+	EmptyObject, ProcessInfo, ProcessKillTreeParams, ProcessListResponse, ProcessSignal,
+	ProcessSignalParams,
+};
+
+pub async fn handle_list() -> Result<ProcessListResponse, AnyError> {
+	let processes = tokio::task::spawn_blocking(list_processes)
+		.await
+		.map_err(|e| wrap(e, "error listing processes"))?;
+	Ok(processes)
+}
+
+fn list_processes() -> ProcessListResponse {
+	let refresh = ProcessRefreshKind::new().with_cpu();
+	let mut sys = System::new();
+	// CPU usage is measured between two refreshes
+	sys.refresh_processes_specifics(refresh);
+	std::thread::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL);
+	sys.refresh_processes_specifics(refresh);
+
+	let mut processes: Vec<ProcessInfo> = sys
+		.processes()
+		.values()
+		.map(|p| ProcessInfo {
+			pid: p.pid().as_u32(),
+			ppid: p.parent().map(|p| p.as_u32()),
+			name: p.name().to_string(),
+			cmd: p.cmd().to_vec(),
+			cpu: p.cpu_usage(),
+			memory: p.memory(),
+			start_time: p.start_time(),
+		})
+		.collect();
+	processes.sort_by_key(|p| p.pid);
+
+	ProcessListResponse { processes }
+}
+
+pub async fn handle_signal(params: ProcessSignalParams) -> Result<EmptyObject, AnyError> {
+	let (signal, name) = match params.signal {
+		ProcessSignal::Hup => (Signal::Hangup, "SIGHUP"),
+		ProcessSignal::Int => (Signal::Interrupt, "SIGINT"),
+		ProcessSignal::Quit => (Signal::Quit, "SIGQUIT"),
+		ProcessSignal::Kill => (Signal::Kill, "SIGKILL"),
+		ProcessSignal::Usr1 => (Signal::User1, "SIGUSR1"),
+		ProcessSignal::Usr2 => (Signal::User2, "SIGUSR2"),
+		ProcessSignal::Term => (Signal::Term, "SIGTERM"),
+		ProcessSignal::Stop => (Signal::Stop, "SIGSTOP"),
+		ProcessSignal::Cont => (Signal::Continue, "SIGCONT"),
+	};
+
+	let pid = params.pid;
+	let sent = tokio::task::spawn_blocking(move || {
+		let mut sys = System::new();
+		find_process(&mut sys, pid)?;
+		Ok::<_, CodeError>(sys.process(Pid::from_u32(pid)).unwrap().kill_with(signal))
+	})
+	.await
+	.map_err(|e| wrap(e, "error signalling process"))??;
+
+	let reason = match sent {
+		Some(true) => return Ok(EmptyObject {}),
+		Some(false) => "the signal was not delivered",
+		None => "the signal is not supported on this platform",
+	};
+
+	Err(CodeError::ProcessSignalFailed {
+		pid,
+		signal: name.to_string(),
+		reason: reason.to_string(),
+	}
+	.into())
+}
+
+pub async fn handle_kill_tree(params: ProcessKillTreeParams) -> Result<EmptyObject, AnyError> {
+	let pid = params.pid;
+	tokio::task::spawn_blocking(move || find_process(&mut System::new(), pid))
+		.await
+		.map_err(|e| wrap(e, "error signalling process"))??;
+
+	kill_tree(pid).await?;
+	Ok(EmptyObject {})
+}
+
+fn find_process(sys: &mut System, pid: u32) -> Result<(), CodeError> {
+	// pid 0 addresses the process group of the CLI in kill(2)
+	if pid == 0 || !sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new()) {
+		return Err(CodeError::ProcessNotFound(pid));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::protocol::ProcessErrorKind;
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_list_and_signal() {
+		let mut child = std::process::Command::new("sleep")
+			.arg("30")
+			.spawn()
+			.unwrap();
+		let pid = child.id();
+
+		let list = handle_list().await.unwrap();
+		let info = list.processes.iter().find(|p| p.pid == pid).unwrap();
+		assert_eq!(info.ppid, Some(std::process::id()));
+		assert_eq!(info.cmd, vec!["sleep", "30"]);
+
+		handle_signal(ProcessSignalParams {
+			pid,
+			signal: ProcessSignal::Term,
+		})
+		.await
+		.unwrap();
+		let status = child.wait().unwrap();
+		assert_eq!(
+			std::os::unix::process::ExitStatusExt::signal(&status),
+			Some(libc::SIGTERM)
+		);
+
+		// the child is reaped, so its pid is no longer in use
+		let err = handle_kill_tree(ProcessKillTreeParams { pid })
+			.await
+			.unwrap_err();
+		assert_eq!(err.rpc_code(), ProcessErrorKind::NotFound as i32);
+	}
+
+	#[tokio::test]
+	async fn test_pid_zero_not_found() {
+		let err = handle_kill_tree(ProcessKillTreeParams { pid: 0 })
+			.await
+			.unwrap_err();
+		assert_eq!(err.rpc_code(), ProcessErrorKind::NotFound as i32);
+	}
+}
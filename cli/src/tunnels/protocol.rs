@@ -8,6 +8,7 @@ use crate::{
 	constants::{PROTOCOL_VERSION, VSCODE_CLI_VERSION},
 	options::Quality,
 	update_service::Platform,
+	util::prereqs::LibcInfo,
 };
 use serde::{Deserialize, Serialize};
 
@@ -127,6 +128,50 @@ pub struct GetEnvResponse {
 	pub os_release: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct GetSystemInfoResponse {
+	pub cli_version: String,
+	/// Architecture the CLI was built for, such as `x86_64` or `aarch64`.
+	pub arch: String,
+	/// Platform of the servers the machine can run, or None if it doesn't
+	/// meet their prerequisites.
+	pub platform: Option<Platform>,
+	pub libc: Option<LibcInfo>,
+	pub cpu: CpuInfo,
+	pub memory: MemoryInfo,
+	/// Disk holding the user's home directory.
+	pub home_disk: Option<DiskUsage>,
+	/// Disk holding the CLI's data directory, where servers are installed.
+	pub data_disk: Option<DiskUsage>,
+	/// Seconds since the machine booted.
+	pub uptime: u64,
+	/// Load average over 1, 5 and 15 minutes, which isn't available on Windows.
+	pub load_average: Option<[f64; 3]>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CpuInfo {
+	/// Number of logical CPUs.
+	pub count: usize,
+	pub physical_count: Option<usize>,
+	pub model: String,
+}
+
+/// Memory in bytes.
+#[derive(Serialize, Deserialize)]
+pub struct MemoryInfo {
+	pub total: u64,
+	pub available: u64,
+}
+
+/// Space in bytes of the disk containing `path`.
+#[derive(Serialize, Deserialize)]
+pub struct DiskUsage {
+	pub path: String,
+	pub total: u64,
+	pub available: u64,
+}
+
 #[derive(Deserialize)]
 pub struct FsStatRequest {
 	pub path: String,
@@ -140,6 +185,197 @@ pub struct FsStatResponse {
 	pub kind: Option<&'static str>,
 }
 
+/// Kind of error returned by the `fs_*` methods, which is sent as the error
+/// code of the response so clients don't have to parse the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsErrorKind {
+	NotFound = 1,
+	PermissionDenied = 2,
+	AlreadyExists = 3,
+	NotADirectory = 4,
+	IsADirectory = 5,
+	DirectoryNotEmpty = 6,
+	InvalidInput = 7,
+	Other = 8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsReaddirParams {
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsDirEntry {
+	pub name: String,
+	/// Type of the entry, without following symlinks. One of the types
+	/// returned by `fs_stat`.
+	#[serde(rename = "type")]
+	pub kind: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsReaddirResponse {
+	pub entries: Vec<FsDirEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsReadParams {
+	pub path: String,
+	/// Offset to start reading at.
+	#[serde(default)]
+	pub offset: u64,
+	/// Maximum number of bytes to read, defaults to the rest of the file.
+	#[serde(default)]
+	pub length: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsReadResponse {
+	/// Number of bytes written to the stream.
+	pub bytes_read: u64,
+	/// Size of the whole file.
+	pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsWriteParams {
+	pub path: String,
+	/// Offset to write at. The file is replaced if not given.
+	#[serde(default)]
+	pub offset: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsWriteResponse {
+	pub bytes_written: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsMkdirParams {
+	pub path: String,
+	/// Also create missing parent directories.
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsRenameParams {
+	pub from: String,
+	pub to: String,
+	/// Replace `to` if it already exists.
+	#[serde(default)]
+	pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsRmParams {
+	pub path: String,
+	/// Remove directories along with their contents.
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsRealpathParams {
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsRealpathResponse {
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FsWatchParams {
+	pub path: String,
+	/// Also watch files in subdirectories.
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsWatchEventKind {
+	Create,
+	Modify,
+	Delete,
+}
+
+/// Change sent on the `fs_watch` stream, where each event is a msgpack object.
+/// The stream ends once the watched path itself is deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FsWatchEvent {
+	#[serde(rename = "type")]
+	pub kind: FsWatchEventKind,
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessInfo {
+	pub pid: u32,
+	pub ppid: Option<u32>,
+	pub name: String,
+	pub cmd: Vec<String>,
+	/// CPU usage in percent, which may exceed 100 for processes using
+	/// several cores.
+	pub cpu: f32,
+	/// Resident memory in bytes.
+	pub memory: u64,
+	/// Start time in seconds since the Unix epoch.
+	pub start_time: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessListResponse {
+	pub processes: Vec<ProcessInfo>,
+}
+
+/// Signal sent by `process_signal` or `spawn_pty`. Only `SIGKILL` is
+/// supported on Windows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessSignal {
+	#[serde(rename = "SIGHUP")]
+	Hup,
+	#[serde(rename = "SIGINT")]
+	Int,
+	#[serde(rename = "SIGQUIT")]
+	Quit,
+	#[serde(rename = "SIGKILL")]
+	Kill,
+	#[serde(rename = "SIGUSR1")]
+	Usr1,
+	#[serde(rename = "SIGUSR2")]
+	Usr2,
+	#[default]
+	#[serde(rename = "SIGTERM")]
+	Term,
+	#[serde(rename = "SIGSTOP")]
+	Stop,
+	#[serde(rename = "SIGCONT")]
+	Cont,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessSignalParams {
+	pub pid: u32,
+	#[serde(default)]
+	pub signal: ProcessSignal,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessKillTreeParams {
+	pub pid: u32,
+}
+
+/// Kind of error returned by the `process_*` methods, which is sent as the
+/// error code of the response like `FsErrorKind`, whose codes it doesn't overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessErrorKind {
+	NotFound = 101,
+	SignalFailed = 102,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CallServerHttpParams {
 	pub path: String,
@@ -171,7 +407,7 @@ impl Default for VersionResponse {
 	}
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SpawnParams {
 	pub command: String,
 	pub args: Vec<String>,
@@ -190,12 +426,35 @@ pub struct AcquireCliParams {
 	pub spawn: SpawnParams,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SpawnResult {
 	pub message: String,
 	pub exit_code: i32,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SpawnPtyParams {
+	#[serde(flatten)]
+	pub spawn: SpawnParams,
+	pub rows: u16,
+	pub cols: u16,
+}
+
+/// Message sent on the control stream of `spawn_pty`, where each message is
+/// a msgpack object.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PtyControl {
+	Resize {
+		rows: u16,
+		cols: u16,
+	},
+	/// Sends a signal to the foreground job of the terminal.
+	Signal {
+		signal: ProcessSignal,
+	},
+}
+
 pub const METHOD_CHALLENGE_ISSUE: &str = "challenge_issue";
 pub const METHOD_CHALLENGE_VERIFY: &str = "challenge_verify";
 
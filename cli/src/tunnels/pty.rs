@@ -0,0 +1,427 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Handler for the `spawn_pty` method of the control server, which runs a
+//! process in a pseudo-terminal. Only supported on Unix.
+
+use tokio::io::DuplexStream;
+
+use crate::log;
+use crate::util::errors::AnyError;
+
+use super::protocol::{SpawnPtyParams, SpawnResult};
+
+#[cfg(unix)]
+pub use unix::handle_spawn_pty;
+
+#[cfg(not(unix))]
+pub async fn handle_spawn_pty(
+	_log: &log::Logger,
+	_io: DuplexStream,
+	_control: DuplexStream,
+	_params: SpawnPtyParams,
+) -> Result<SpawnResult, AnyError> {
+	Err(crate::util::errors::CodeError::PtyUnsupported.into())
+}
+
+#[cfg(unix)]
+mod unix {
+	use std::io;
+	use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+	use std::pin::Pin;
+	use std::process::Stdio;
+	use std::task::{ready, Context, Poll};
+	use std::time::Duration;
+
+	use futures::StreamExt;
+	use tokio::io::unix::AsyncFd;
+	use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+	use tokio::pin;
+	use tokio_util::codec::FramedRead;
+
+	use crate::msgpack_rpc::MsgPackCodec;
+	use crate::tunnels::protocol::{ProcessSignal, PtyControl};
+	use crate::util::errors::{wrap, CodeError};
+
+	use super::*;
+
+	/// How long to wait for remaining output once the process exits, in case
+	/// it left background jobs holding the terminal open.
+	const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+	/// Runs the process in a new pseudo-terminal, connecting the terminal to
+	/// the `io` stream. `resize` and `signal` messages are read from the
+	/// `control` stream while it runs.
+	pub async fn handle_spawn_pty(
+		log: &log::Logger,
+		io: DuplexStream,
+		control: DuplexStream,
+		params: SpawnPtyParams,
+	) -> Result<SpawnResult, AnyError> {
+		let spawn = &params.spawn;
+		debug!(
+			log,
+			"requested to spawn {} in a pty with args {:?}", spawn.command, spawn.args
+		);
+
+		let (master, slave) =
+			open_pty(params.rows, params.cols).map_err(|e| wrap(e, "error opening pty"))?;
+
+		let mut p = tokio::process::Command::new(&spawn.command);
+		p.args(&spawn.args);
+		p.env("TERM", "xterm-256color");
+		p.envs(&spawn.env);
+		let stdio = || {
+			slave
+				.try_clone()
+				.map(Stdio::from)
+				.map_err(|e| wrap(e, "error opening pty"))
+		};
+		p.stdin(stdio()?);
+		p.stdout(stdio()?);
+		p.stderr(stdio()?);
+		if let Some(cwd) = &spawn.cwd {
+			p.current_dir(cwd);
+		}
+		// start a new session with the pty as its controlling terminal, so
+		// that job control works and the process is hung up with the terminal
+		unsafe {
+			p.pre_exec(|| {
+				if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+					return Err(io::Error::last_os_error());
+				}
+				Ok(())
+			});
+		}
+
+		let mut child = p.spawn().map_err(CodeError::ProcessSpawnFailed)?;
+		// close our copies of the terminal, so reads end once the process does
+		drop(p);
+		drop(slave);
+		// the pid is only missing once the process was reaped, which can't have
+		// happened yet, but signalling pid 0 would hit the CLI's own process group
+		let pid = match child.id() {
+			Some(p) => p as libc::pid_t,
+			None => {
+				let e = io::Error::other("the process has no pid");
+				return Err(CodeError::ProcessSpawnFailed(e).into());
+			}
+		};
+
+		let (mut input, mut output) = tokio::io::split(io);
+		let mut control = FramedRead::new(control, MsgPackCodec::<PtyControl>::new());
+
+		let mut master_out = &master;
+		let output_pump = async move {
+			let r = tokio::io::copy(&mut master_out, &mut output).await;
+			let _ = output.shutdown().await;
+			r
+		};
+		let mut master_in = &master;
+		let input_pump = tokio::io::copy(&mut input, &mut master_in);
+		pin!(output_pump);
+		pin!(input_pump);
+
+		let mut output_done = false;
+		let mut input_done = false;
+		let mut control_done = false;
+		let status = loop {
+			tokio::select! {
+				r = child.wait() => break r,
+				r = &mut output_pump, if !output_done => {
+					output_done = true;
+					// the client went away, so hang up like a closed terminal would
+					if r.is_err() {
+						unsafe { libc::killpg(pid, libc::SIGHUP) };
+					}
+				},
+				_ = &mut input_pump, if !input_done => input_done = true,
+				msg = control.next(), if !control_done => match msg {
+					Some(Ok(m)) => handle_control(log, &master, pid, m.obj),
+					Some(Err(e)) => {
+						warning!(log, "invalid pty control message: {}", e);
+						control_done = true;
+					}
+					None => control_done = true,
+				},
+			}
+		};
+
+		if !output_done {
+			let _ = tokio::time::timeout(DRAIN_TIMEOUT, &mut output_pump).await;
+		}
+
+		let r = match status {
+			Ok(e) => SpawnResult {
+				message: e.to_string(),
+				exit_code: e.code().unwrap_or(-1),
+			},
+			Err(e) => SpawnResult {
+				message: e.to_string(),
+				exit_code: -1,
+			},
+		};
+
+		debug!(
+			log,
+			"spawned {} in a pty exited with code {}", spawn.command, r.exit_code
+		);
+
+		Ok(r)
+	}
+
+	fn handle_control(log: &log::Logger, master: &PtyMaster, pid: libc::pid_t, msg: PtyControl) {
+		match msg {
+			PtyControl::Resize { rows, cols } => {
+				if let Err(e) = master.resize(rows, cols) {
+					warning!(log, "error resizing pty: {}", e);
+				}
+			}
+			PtyControl::Signal { signal } => {
+				// signal the foreground job, like the terminal does for Ctrl+C
+				let pgrp = master.foreground_process_group().unwrap_or(pid);
+				if unsafe { libc::killpg(pgrp, signal_number(signal)) } == -1 {
+					warning!(
+						log,
+						"error sending {:?} to the pty: {}",
+						signal,
+						io::Error::last_os_error()
+					);
+				}
+			}
+		}
+	}
+
+	fn signal_number(signal: ProcessSignal) -> libc::c_int {
+		match signal {
+			ProcessSignal::Hup => libc::SIGHUP,
+			ProcessSignal::Int => libc::SIGINT,
+			ProcessSignal::Quit => libc::SIGQUIT,
+			ProcessSignal::Kill => libc::SIGKILL,
+			ProcessSignal::Usr1 => libc::SIGUSR1,
+			ProcessSignal::Usr2 => libc::SIGUSR2,
+			ProcessSignal::Term => libc::SIGTERM,
+			ProcessSignal::Stop => libc::SIGSTOP,
+			ProcessSignal::Cont => libc::SIGCONT,
+		}
+	}
+
+	fn winsize(rows: u16, cols: u16) -> libc::winsize {
+		libc::winsize {
+			ws_row: rows,
+			ws_col: cols,
+			ws_xpixel: 0,
+			ws_ypixel: 0,
+		}
+	}
+
+	/// Opens a pseudo-terminal, returning its master and its slave.
+	fn open_pty(rows: u16, cols: u16) -> io::Result<(PtyMaster, OwnedFd)> {
+		let mut master = -1;
+		let mut slave = -1;
+		let mut size = winsize(rows, cols);
+		let r = unsafe {
+			libc::openpty(
+				&mut master,
+				&mut slave,
+				std::ptr::null_mut(),
+				std::ptr::null_mut(),
+				&mut size as *mut _,
+			)
+		};
+		if r == -1 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let (master, slave) =
+			unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+		for fd in [&master, &slave] {
+			if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		let flags = unsafe { libc::fcntl(master.as_raw_fd(), libc::F_GETFL) };
+		if flags == -1
+			|| unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) }
+				== -1
+		{
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok((PtyMaster(AsyncFd::new(master)?), slave))
+	}
+
+	/// Master side of a pseudo-terminal. Reads and writes go through shared
+	/// references, so input and output can be pumped at the same time.
+	struct PtyMaster(AsyncFd<OwnedFd>);
+
+	impl PtyMaster {
+		fn resize(&self, rows: u16, cols: u16) -> io::Result<()> {
+			let size = winsize(rows, cols);
+			if unsafe { libc::ioctl(self.0.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+				return Err(io::Error::last_os_error());
+			}
+			Ok(())
+		}
+
+		fn foreground_process_group(&self) -> Option<libc::pid_t> {
+			match unsafe { libc::tcgetpgrp(self.0.as_raw_fd()) } {
+				pgrp if pgrp > 0 => Some(pgrp),
+				_ => None,
+			}
+		}
+	}
+
+	impl AsyncRead for &PtyMaster {
+		fn poll_read(
+			self: Pin<&mut Self>,
+			cx: &mut Context<'_>,
+			buf: &mut ReadBuf<'_>,
+		) -> Poll<io::Result<()>> {
+			loop {
+				let mut guard = ready!(self.0.poll_read_ready(cx))?;
+				let unfilled = buf.initialize_unfilled();
+				let r = guard.try_io(|fd| {
+					let n = unsafe {
+						libc::read(
+							fd.as_raw_fd(),
+							unfilled.as_mut_ptr() as *mut _,
+							unfilled.len(),
+						)
+					};
+					if n == -1 {
+						Err(io::Error::last_os_error())
+					} else {
+						Ok(n as usize)
+					}
+				});
+
+				match r {
+					Ok(Ok(n)) => {
+						buf.advance(n);
+						return Poll::Ready(Ok(()));
+					}
+					// Linux fails reads once every process closed the terminal
+					Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => {
+						return Poll::Ready(Ok(()))
+					}
+					Ok(Err(e)) => return Poll::Ready(Err(e)),
+					Err(_would_block) => continue,
+				}
+			}
+		}
+	}
+
+	impl AsyncWrite for &PtyMaster {
+		fn poll_write(
+			self: Pin<&mut Self>,
+			cx: &mut Context<'_>,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			loop {
+				let mut guard = ready!(self.0.poll_write_ready(cx))?;
+				let r = guard.try_io(|fd| {
+					let n =
+						unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr() as *const _, buf.len()) };
+					if n == -1 {
+						Err(io::Error::last_os_error())
+					} else {
+						Ok(n as usize)
+					}
+				});
+
+				match r {
+					Ok(r) => return Poll::Ready(r),
+					Err(_would_block) => continue,
+				}
+			}
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use std::collections::HashMap;
+	use std::time::Duration;
+
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+	use crate::tunnels::protocol::{ProcessSignal, PtyControl, SpawnParams};
+
+	async fn read_until(io: &mut DuplexStream, output: &mut String, s: &str) {
+		let mut buf = [0u8; 1024];
+		while !output.contains(s) {
+			let n = tokio::time::timeout(Duration::from_secs(5), io.read(&mut buf))
+				.await
+				.unwrap_or_else(|_| panic!("expected {:?} in {:?}", s, output))
+				.unwrap();
+			assert!(n > 0, "expected {:?} in {:?}", s, output);
+			output.push_str(&String::from_utf8_lossy(&buf[..n]));
+		}
+	}
+
+	async fn send_control(control: &mut DuplexStream, msg: PtyControl) {
+		let msg = rmp_serde::to_vec_named(&msg).unwrap();
+		control.write_all(&msg).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_spawn_pty() {
+		let (mut io, server_io) = tokio::io::duplex(1 << 16);
+		let (mut control, server_control) = tokio::io::duplex(1 << 16);
+		let params = SpawnPtyParams {
+			spawn: SpawnParams {
+				command: "sh".to_string(),
+				args: vec![
+					"-c".to_string(),
+					"trap 'exit 3' TERM; stty size; read a; stty size; \
+					 while :; do sleep 0.1; done"
+						.to_string(),
+				],
+				cwd: None,
+				env: HashMap::new(),
+			},
+			rows: 30,
+			cols: 100,
+		};
+		let spawned = tokio::spawn(async move {
+			handle_spawn_pty(&log::Logger::test(), server_io, server_control, params).await
+		});
+
+		let mut output = String::new();
+		read_until(&mut io, &mut output, "30 100").await;
+
+		send_control(
+			&mut control,
+			PtyControl::Resize {
+				rows: 40,
+				cols: 120,
+			},
+		)
+		.await;
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		io.write_all(b"hello\n").await.unwrap();
+		read_until(&mut io, &mut output, "40 120").await;
+
+		send_control(
+			&mut control,
+			PtyControl::Signal {
+				signal: ProcessSignal::Term,
+			},
+		)
+		.await;
+		let r = spawned.await.unwrap().unwrap();
+		assert_eq!(r.exit_code, 3);
+	}
+}
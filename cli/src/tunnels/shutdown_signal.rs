@@ -16,6 +16,8 @@ use crate::util::{
 #[derive(Copy, Clone)]
 pub enum ShutdownSignal {
 	CtrlC,
+	Terminated,
+	IdleTimeout,
 	ParentProcessKilled(Pid),
 	ExeUninstalled,
 	ServiceStopped,
@@ -27,6 +29,8 @@ impl fmt::Display for ShutdownSignal {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			ShutdownSignal::CtrlC => write!(f, "Ctrl-C received"),
+			ShutdownSignal::Terminated => write!(f, "SIGTERM received"),
+			ShutdownSignal::IdleTimeout => write!(f, "Idle exit timeout reached"),
 			ShutdownSignal::ParentProcessKilled(p) => {
 				write!(f, "Parent process {} no longer exists", p)
 			}
@@ -44,6 +48,9 @@ impl fmt::Display for ShutdownSignal {
 
 pub enum ShutdownRequest {
 	CtrlC,
+	/// SIGTERM on Unix, such as when a service manager stops the process.
+	/// Never received on Windows.
+	Terminated,
 	ParentProcessKilled(Pid),
 	ExeUninstalled(PathBuf),
 	Derived(Box<dyn Receivable<ShutdownSignal> + Send>),
@@ -57,6 +64,19 @@ impl ShutdownRequest {
 				ctrl_c.await.ok();
 				Some(ShutdownSignal::CtrlC)
 			}
+			ShutdownRequest::Terminated => {
+				#[cfg(unix)]
+				{
+					use tokio::signal::unix::{signal, SignalKind};
+					if let Ok(mut s) = signal(SignalKind::terminate()) {
+						s.recv().await;
+						return Some(ShutdownSignal::Terminated);
+					}
+				}
+
+				futures::future::pending::<()>().await;
+				None
+			}
 			ShutdownRequest::ParentProcessKilled(pid) => {
 				wait_until_process_exits(pid, 2000).await;
 				Some(ShutdownSignal::ParentProcessKilled(pid))
@@ -0,0 +1,104 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Handler for the `get_system_info` method of the control server, which
+//! describes the machine so clients can check it's able to run a server.
+
+use std::path::{Path, PathBuf};
+
+use sysinfo::{CpuExt, System, SystemExt};
+
+use crate::constants::VSCODE_CLI_VERSION;
+use crate::state::LauncherPaths;
+use crate::util::errors::{wrap, AnyError};
+use crate::util::machine::disk_space;
+use crate::util::prereqs::PreReqChecker;
+
+use super::protocol::{CpuInfo, DiskUsage, GetSystemInfoResponse, MemoryInfo};
+
+pub async fn handle_get_system_info(
+	launcher_paths: &LauncherPaths,
+) -> Result<GetSystemInfoResponse, AnyError> {
+	let checker = PreReqChecker::new();
+	let (platform, libc) = tokio::join!(checker.verify(), checker.libc());
+
+	let home = dirs::home_dir();
+	let data = launcher_paths.root().to_owned();
+	let mut info = tokio::task::spawn_blocking(move || read_system_info(home, data))
+		.await
+		.map_err(|e| wrap(e, "error reading system info"))?;
+	info.platform = platform.ok();
+	info.libc = libc;
+
+	Ok(info)
+}
+
+/// Reads everything but the platform and libc, which are found asynchronously.
+fn read_system_info(home: Option<PathBuf>, data: PathBuf) -> GetSystemInfoResponse {
+	let mut sys = System::new();
+	sys.refresh_cpu();
+	sys.refresh_memory();
+
+	let load_average = if cfg!(windows) {
+		None
+	} else {
+		let l = sys.load_average();
+		Some([l.one, l.five, l.fifteen])
+	};
+
+	GetSystemInfoResponse {
+		cli_version: VSCODE_CLI_VERSION.unwrap_or("dev").to_string(),
+		arch: std::env::consts::ARCH.to_string(),
+		platform: None,
+		libc: None,
+		cpu: CpuInfo {
+			count: sys.cpus().len(),
+			physical_count: sys.physical_core_count(),
+			model: sys
+				.cpus()
+				.first()
+				.map(|c| c.brand().trim().to_string())
+				.unwrap_or_default(),
+		},
+		memory: MemoryInfo {
+			total: sys.total_memory(),
+			available: sys.available_memory(),
+		},
+		home_disk: home.as_deref().and_then(disk_usage),
+		data_disk: disk_usage(&data),
+		uptime: sys.uptime(),
+		load_average,
+	}
+}
+
+fn disk_usage(path: &Path) -> Option<DiskUsage> {
+	let (total, available) = disk_space(path).ok()?;
+	Some(DiskUsage {
+		path: path.to_string_lossy().to_string(),
+		total,
+		available,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_get_system_info() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+
+		let info = handle_get_system_info(&paths).await.unwrap();
+		assert_eq!(info.arch, std::env::consts::ARCH);
+		assert!(info.cpu.count > 0);
+		assert!(info.memory.total >= info.memory.available);
+		let disk = info.data_disk.unwrap();
+		assert_eq!(disk.path, dir.path().to_string_lossy());
+		assert!(disk.total >= disk.available);
+		#[cfg(target_os = "linux")]
+		assert!(info.libc.is_some());
+	}
+}
@@ -3,26 +3,39 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{ffi::OsStr, fmt, path::Path};
+use std::{
+	ffi::OsStr,
+	fmt,
+	path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
 	constants::VSCODE_CLI_UPDATE_ENDPOINT,
 	debug, log, options, spanf,
 	util::{
-		errors::{AnyError, CodeError, WrappedError},
-		http::{BoxedHttp, SimpleResponse},
+		errors::{wrap, AnyError, CodeError, WrappedError},
+		http::{self, BoxedHttp, SimpleResponse},
 		io::ReportCopyProgress,
 		tar, zipper,
 	},
 };
 
+/// Environment variable that allows downloads to be used when there's no
+/// SHA-256 hash to verify them against.
+const ALLOW_UNVERIFIED_ENV_VAR: &str = "VSCODE_CLI_ALLOW_UNVERIFIED_DOWNLOADS";
+
 /// Implementation of the VS Code Update service for use in the CLI.
 #[derive(Clone)]
 pub struct UpdateService {
 	client: BoxedHttp,
 	log: log::Logger,
+	/// Overrides the update endpoint the CLI was built with.
+	endpoint: Option<String>,
+	/// Whether downloads without a hash are used, see `ALLOW_UNVERIFIED_ENV_VAR`.
+	allow_unverified: bool,
 }
 
 /// Describes a specific release, can be created manually or returned from the update service.
@@ -45,6 +58,8 @@ impl std::fmt::Display for Release {
 struct UpdateServerVersion {
 	pub version: String,
 	pub name: String,
+	#[serde(default)]
+	pub sha256hash: Option<String>,
 }
 
 fn quality_download_segment(quality: options::Quality) -> &'static str {
@@ -55,13 +70,28 @@ fn quality_download_segment(quality: options::Quality) -> &'static str {
 	}
 }
 
-fn get_update_endpoint() -> Result<&'static str, CodeError> {
-	VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(|| CodeError::UpdatesNotConfigured("no service url"))
-}
-
 impl UpdateService {
 	pub fn new(log: log::Logger, http: BoxedHttp) -> Self {
-		UpdateService { client: http, log }
+		UpdateService {
+			client: http,
+			log,
+			endpoint: None,
+			allow_unverified: std::env::var(ALLOW_UNVERIFIED_ENV_VAR).is_ok(),
+		}
+	}
+
+	/// Uses the given update endpoint instead of the one the CLI was built with.
+	pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+		self.endpoint = Some(endpoint.into());
+		self
+	}
+
+	fn get_update_endpoint(&self) -> Result<&str, CodeError> {
+		match &self.endpoint {
+			Some(e) => Ok(e),
+			None => VSCODE_CLI_UPDATE_ENDPOINT
+				.ok_or_else(|| CodeError::UpdatesNotConfigured("no service url")),
+		}
 	}
 
 	pub async fn get_release_by_semver_version(
@@ -71,7 +101,7 @@ impl UpdateService {
 		quality: options::Quality,
 		version: &str,
 	) -> Result<Release, AnyError> {
-		let update_endpoint = get_update_endpoint()?;
+		let update_endpoint = self.get_update_endpoint()?;
 		let download_segment = target
 			.download_segment(platform)
 			.ok_or_else(|| CodeError::UnsupportedPlatform(platform.to_string()))?;
@@ -112,7 +142,7 @@ impl UpdateService {
 		target: TargetKind,
 		quality: options::Quality,
 	) -> Result<Release, AnyError> {
-		let update_endpoint = get_update_endpoint()?;
+		let update_endpoint = self.get_update_endpoint()?;
 		let download_segment = target
 			.download_segment(platform)
 			.ok_or_else(|| CodeError::UnsupportedPlatform(platform.to_string()))?;
@@ -147,7 +177,7 @@ impl UpdateService {
 
 	/// Gets the download stream for the release.
 	pub async fn get_download_stream(&self, release: &Release) -> Result<SimpleResponse, AnyError> {
-		let update_endpoint = get_update_endpoint()?;
+		let update_endpoint = self.get_update_endpoint()?;
 		let download_segment = release
 			.target
 			.download_segment(release.platform)
@@ -168,6 +198,142 @@ impl UpdateService {
 
 		Ok(response)
 	}
+
+	/// Downloads the release into the directory, returning the path of the
+	/// archive. The archive is verified against the SHA-256 hash published by
+	/// the update service, or else against a `.sha256` file next to the download
+	/// as provided by mirrors. Fails if neither is available, unless
+	/// `ALLOW_UNVERIFIED_ENV_VAR` is set.
+	pub async fn download_release(
+		&self,
+		release: &Release,
+		dir: &Path,
+		progress: impl ReportCopyProgress,
+	) -> Result<PathBuf, AnyError> {
+		let published = self.get_published_hash(release).await;
+		let response = self.get_download_stream(release).await?;
+		let url = response.url.clone();
+		let archive_path = dir.join(response.url_path_basename().unwrap());
+		http::download_into_file(&archive_path, progress, response).await?;
+
+		let sidecar = match (&published, url) {
+			(None, Some(url)) => self.get_sidecar_hash(url).await,
+			_ => None,
+		};
+		let expected = match published.or(sidecar) {
+			Some(h) => h,
+			None if self.allow_unverified => {
+				warning!(
+					self.log,
+					"No SHA-256 hash is published for {}, using it unverified since {} is set",
+					release,
+					ALLOW_UNVERIFIED_ENV_VAR
+				);
+				return Ok(archive_path);
+			}
+			None => return Err(CodeError::DownloadNotVerified(release.to_string()).into()),
+		};
+
+		let actual = sha256_file(&archive_path).await?;
+		if !actual.eq_ignore_ascii_case(&expected) {
+			return Err(CodeError::DownloadChecksumMismatch { expected, actual }.into());
+		}
+
+		debug!(self.log, "Verified SHA-256 of {}: {}", release, actual);
+		Ok(archive_path)
+	}
+
+	/// Gets the SHA-256 hash of the release's download from the update service.
+	async fn get_published_hash(&self, release: &Release) -> Option<String> {
+		let update_endpoint = self.get_update_endpoint().ok()?;
+		let download_segment = release.target.download_segment(release.platform)?;
+		let url = format!(
+			"{}/api/versions/commit:{}/{}/{}",
+			update_endpoint,
+			release.commit,
+			download_segment,
+			quality_download_segment(release.quality),
+		);
+
+		let mut response = match self.client.make_request("GET", url).await {
+			Ok(r) if r.status_code.is_success() => r,
+			Ok(r) => {
+				warning!(
+					self.log,
+					"No published hash for {}: {}",
+					release,
+					r.status_code
+				);
+				return None;
+			}
+			Err(e) => {
+				warning!(
+					self.log,
+					"Error getting published hash for {}: {}",
+					release,
+					e
+				);
+				return None;
+			}
+		};
+
+		match response.json::<UpdateServerVersion>().await {
+			Ok(v) => v.sha256hash,
+			Err(e) => {
+				warning!(
+					self.log,
+					"Error reading published hash for {}: {}",
+					release,
+					e
+				);
+				None
+			}
+		}
+	}
+
+	/// Gets the hash from a `.sha256` file next to the downloaded file. The
+	/// file contains the hex-encoded hash, optionally followed by the file name.
+	async fn get_sidecar_hash(&self, mut url: url::Url) -> Option<String> {
+		url.set_path(&format!("{}.sha256", url.path()));
+		let mut response = self
+			.client
+			.make_request("GET", url.to_string())
+			.await
+			.ok()?;
+		if !response.status_code.is_success() {
+			return None;
+		}
+
+		let mut contents = String::new();
+		tokio::io::AsyncReadExt::read_to_string(&mut response.read, &mut contents)
+			.await
+			.ok()?;
+		let hash = contents.split_whitespace().next()?;
+		if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+			warning!(self.log, "Ignoring invalid SHA-256 hash at {}", url);
+			return None;
+		}
+
+		Some(hash.to_string())
+	}
+}
+
+async fn sha256_file(path: &Path) -> Result<String, WrappedError> {
+	let path = path.to_owned();
+	tokio::task::spawn_blocking(move || {
+		let mut file =
+			std::fs::File::open(&path).map_err(|e| wrap(e, "error opening downloaded file"))?;
+		let mut hasher = Sha256::new();
+		std::io::copy(&mut file, &mut hasher)
+			.map_err(|e| wrap(e, "error reading downloaded file"))?;
+		Ok(hasher
+			.finalize()
+			.iter()
+			.map(|b| format!("{:02x}", b))
+			.collect())
+	})
+	.await
+	.unwrap()
 }
 
 pub fn unzip_downloaded_release<T>(
@@ -319,3 +485,167 @@ impl fmt::Display for Platform {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::convert::Infallible;
+	use std::net::SocketAddr;
+	use std::sync::Arc;
+
+	use hyper::service::{make_service_fn, service_fn};
+	use hyper::{Body, Request, Response, Server};
+
+	use super::*;
+	use crate::download_cache::DownloadCache;
+	use crate::util::http::ReqwestSimpleHttp;
+	use crate::util::io::SilentCopyProgress;
+
+	const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+	const ARCHIVE: &[u8] = b"not really a tarball";
+	/// SHA-256 of `ARCHIVE`
+	const ARCHIVE_HASH: &str = "b3b786ecd6e4f6ab3398d0b1bfd62dbc47534c8723cd3802f642f7203a3b55d7";
+
+	/// Serves a single CLI release, optionally with a published hash and a
+	/// `.sha256` file next to the download.
+	fn start_fake_update_server(published: Option<&str>, sidecar: Option<&str>) -> SocketAddr {
+		let published = published.map(|s| s.to_string());
+		let sidecar = sidecar.map(|s| s.to_string());
+		let make_svc = make_service_fn(move |_| {
+			let (published, sidecar) = (published.clone(), sidecar.clone());
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+					let res = match req.uri().path() {
+						p if p
+							== format!("/api/versions/commit:{}/cli-linux-x64/stable", COMMIT) =>
+						{
+							let v = serde_json::json!({
+								"version": COMMIT,
+								"name": "1.0.0",
+								"sha256hash": published,
+							});
+							Response::new(Body::from(v.to_string()))
+						}
+						p if p == format!("/commit:{}/cli-linux-x64/stable", COMMIT) => {
+							Response::builder()
+								.status(302)
+								.header("Location", "/download/code.tar.gz")
+								.body(Body::empty())
+								.unwrap()
+						}
+						"/download/code.tar.gz" => Response::new(Body::from(ARCHIVE)),
+						"/download/code.tar.gz.sha256" if sidecar.is_some() => Response::new(
+							Body::from(format!("{}  code.tar.gz\n", sidecar.as_ref().unwrap())),
+						),
+						_ => Response::builder().status(404).body(Body::empty()).unwrap(),
+					};
+					async move { Ok::<_, Infallible>(res) }
+				}))
+			}
+		});
+
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+		let addr = server.local_addr();
+		tokio::spawn(server);
+		addr
+	}
+
+	fn update_service(addr: SocketAddr) -> UpdateService {
+		UpdateService::new(log::Logger::test(), Arc::new(ReqwestSimpleHttp::new()))
+			.with_endpoint(format!("http://{}", addr))
+	}
+
+	fn release() -> Release {
+		Release {
+			name: "1.0.0".to_string(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Cli,
+			quality: options::Quality::Stable,
+			commit: COMMIT.to_string(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_download_release_verifies_published_hash() {
+		let addr = start_fake_update_server(Some(ARCHIVE_HASH), None);
+		let dir = tempfile::tempdir().unwrap();
+
+		let path = update_service(addr)
+			.download_release(&release(), dir.path(), SilentCopyProgress())
+			.await
+			.unwrap();
+		assert_eq!(path, dir.path().join("code.tar.gz"));
+		assert_eq!(std::fs::read(path).unwrap(), ARCHIVE);
+	}
+
+	#[tokio::test]
+	async fn test_download_release_verifies_sidecar_hash() {
+		let addr = start_fake_update_server(None, Some(ARCHIVE_HASH));
+		let dir = tempfile::tempdir().unwrap();
+
+		update_service(addr)
+			.download_release(&release(), dir.path(), SilentCopyProgress())
+			.await
+			.unwrap();
+
+		let addr = start_fake_update_server(None, Some(&"0".repeat(64)));
+		let err = update_service(addr)
+			.download_release(&release(), dir.path(), SilentCopyProgress())
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DownloadChecksumMismatch { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn test_download_release_without_hash() {
+		let addr = start_fake_update_server(None, None);
+		let dir = tempfile::tempdir().unwrap();
+
+		let mut service = update_service(addr);
+		service.allow_unverified = false;
+		let err = service
+			.download_release(&release(), dir.path(), SilentCopyProgress())
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			AnyError::CodeError(CodeError::DownloadNotVerified(_))
+		));
+
+		service.allow_unverified = true;
+		service
+			.download_release(&release(), dir.path(), SilentCopyProgress())
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_download_release_mismatch_removes_staging_dir() {
+		let addr = start_fake_update_server(Some(&"0".repeat(64)), Some(ARCHIVE_HASH));
+		let service = update_service(addr);
+		let cache_dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(cache_dir.path().to_owned());
+
+		let result = cache
+			.create(COMMIT, |target_dir| async move {
+				let tmpdir = tempfile::tempdir().unwrap();
+				let archive_path = service
+					.download_release(&release(), tmpdir.path(), SilentCopyProgress())
+					.await?;
+				unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
+				Ok(())
+			})
+			.await;
+
+		match result {
+			Err(AnyError::CodeError(CodeError::DownloadChecksumMismatch { expected, actual })) => {
+				assert_eq!(expected, "0".repeat(64));
+				assert_eq!(actual, ARCHIVE_HASH);
+			}
+			_ => panic!("expected a checksum mismatch"),
+		}
+		assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 0);
+	}
+}
@@ -13,9 +13,11 @@ pub mod io;
 pub mod machine;
 pub mod prereqs;
 pub mod ring_buffer;
+pub mod socket_activation;
 pub mod sync;
 pub use is_integrated::*;
 pub mod app_lock;
+pub mod auth_lockout;
 pub mod file_lock;
 pub mod os;
 pub mod tar;
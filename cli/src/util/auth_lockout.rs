@@ -0,0 +1,250 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+/// Number of failed attempts a client can make before it's locked out.
+const ALLOWED_FAILURES: u32 = 5;
+/// Lockout after the first failure past `ALLOWED_FAILURES`. Each further
+/// failure doubles it, up to `MAX_LOCKOUT`.
+const INITIAL_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten once a client hasn't failed for this long.
+const FORGET_FAILURES_AFTER: Duration = Duration::from_secs(60 * 60);
+
+struct ClientFailures {
+	count: u32,
+	last_failure: Instant,
+	locked_until: Option<Instant>,
+}
+
+/// Counts failed authentication attempts per client address, and locks out
+/// clients that fail too often with an exponential backoff. Clients without
+/// an address, such as ones connected over a socket, can't be told apart, so
+/// they're never locked out, since one of them could otherwise lock out all.
+///
+/// Clients behind a reverse proxy all connect from the proxy's address, so
+/// for proxies that are trusted the address they forward is used instead, see
+/// `client_address`. Proxies that aren't trusted are locked out as one client.
+pub struct AuthLockout {
+	log: log::Logger,
+	clients: Mutex<HashMap<IpAddr, ClientFailures>>,
+	/// Totals across all clients, for metrics.
+	failures: AtomicU64,
+	lockouts: AtomicU64,
+}
+
+impl AuthLockout {
+	pub fn new(log: log::Logger) -> Self {
+		Self {
+			log,
+			clients: Mutex::default(),
+			failures: AtomicU64::new(0),
+			lockouts: AtomicU64::new(0),
+		}
+	}
+
+	/// Gets how much longer the client is locked out for, if it is.
+	pub fn locked_out_for(&self, client: Option<IpAddr>) -> Option<Duration> {
+		let clients = self.clients.lock().unwrap();
+		let locked_until = clients.get(&client?)?.locked_until?;
+		locked_until.checked_duration_since(Instant::now())
+	}
+
+	/// Records a failed attempt by the client. Returns the lockout duration if
+	/// the client is now locked out.
+	pub fn record_failure(&self, client: Option<IpAddr>) -> Option<Duration> {
+		self.failures.fetch_add(1, Ordering::Relaxed);
+		let client = match client {
+			Some(c) => c,
+			None => {
+				debug!(
+					self.log,
+					"Failed authentication attempt from a socket client"
+				);
+				return None;
+			}
+		};
+
+		let now = Instant::now();
+		let mut clients = self.clients.lock().unwrap();
+		clients.retain(|_, c| now.duration_since(c.last_failure) < FORGET_FAILURES_AFTER);
+
+		let failures = clients.entry(client).or_insert(ClientFailures {
+			count: 0,
+			last_failure: now,
+			locked_until: None,
+		});
+		failures.count += 1;
+		failures.last_failure = now;
+		debug!(
+			self.log,
+			"Failed authentication attempt {} from {}", failures.count, client
+		);
+
+		if failures.count <= ALLOWED_FAILURES {
+			return None;
+		}
+
+		let doublings = (failures.count - ALLOWED_FAILURES - 1).min(16);
+		let lockout = (INITIAL_LOCKOUT * 2u32.pow(doublings)).min(MAX_LOCKOUT);
+		failures.locked_until = Some(now + lockout);
+		self.lockouts.fetch_add(1, Ordering::Relaxed);
+		warning!(
+			self.log,
+			"{} failed authentication attempts from {}, locking out for {}s",
+			failures.count,
+			client,
+			lockout.as_secs()
+		);
+
+		Some(lockout)
+	}
+
+	/// Records a successful attempt, forgetting the client's failures.
+	pub fn record_success(&self, client: Option<IpAddr>) {
+		if let Some(c) = client {
+			self.clients.lock().unwrap().remove(&c);
+		}
+	}
+
+	/// Gets the number of failed attempts recorded.
+	pub fn failures(&self) -> u64 {
+		self.failures.load(Ordering::Relaxed)
+	}
+
+	/// Gets the number of times a client was locked out.
+	pub fn lockouts(&self) -> u64 {
+		self.lockouts.load(Ordering::Relaxed)
+	}
+}
+
+/// Gets the address to count a client's failures against. For requests from
+/// a trusted proxy, it's the last address in `forwarded_for` (the values of
+/// `X-Forwarded-For` headers) that isn't also a trusted proxy. If there's no
+/// such address, the client can't be told apart and None is returned.
+pub fn client_address<'a>(
+	peer: Option<IpAddr>,
+	forwarded_for: impl Iterator<Item = &'a str>,
+	trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+	let peer = peer?;
+	if !trusted_proxies.contains(&peer) {
+		return Some(peer);
+	}
+
+	let forwarded: Vec<&str> = forwarded_for.flat_map(|h| h.split(',')).collect();
+	for addr in forwarded.into_iter().rev() {
+		let addr = addr.trim();
+		let ip = match addr.parse::<IpAddr>() {
+			Ok(ip) => ip,
+			// some proxies also include the port
+			Err(_) => addr.parse::<SocketAddr>().ok()?.ip(),
+		};
+		if !trusted_proxies.contains(&ip) {
+			return Some(ip);
+		}
+	}
+
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_lockout() {
+		let lockout = AuthLockout::new(log::Logger::test());
+		let client = Some(IpAddr::from([192, 0, 2, 1]));
+		let other = Some(IpAddr::from([192, 0, 2, 2]));
+
+		for _ in 0..ALLOWED_FAILURES {
+			assert_eq!(lockout.record_failure(client), None);
+		}
+		assert!(lockout.locked_out_for(client).is_none());
+
+		assert_eq!(lockout.record_failure(client), Some(INITIAL_LOCKOUT));
+		assert_eq!(lockout.record_failure(client), Some(INITIAL_LOCKOUT * 2));
+		assert_eq!(lockout.record_failure(client), Some(INITIAL_LOCKOUT * 4));
+		assert!(lockout.locked_out_for(client).is_some());
+		assert!(lockout.locked_out_for(other).is_none());
+		assert!(lockout.locked_out_for(None).is_none());
+
+		for _ in 0..32 {
+			lockout.record_failure(client);
+		}
+		assert_eq!(lockout.record_failure(client), Some(MAX_LOCKOUT));
+
+		lockout.record_success(client);
+		assert!(lockout.locked_out_for(client).is_none());
+		assert_eq!(lockout.record_failure(client), None);
+		assert_eq!(lockout.failures(), 42);
+		assert_eq!(lockout.lockouts(), 36);
+	}
+
+	#[test]
+	fn test_socket_clients_not_locked_out() {
+		let lockout = AuthLockout::new(log::Logger::test());
+
+		// one socket client failing can't lock out another, which has no
+		// address to tell it apart by
+		for _ in 0..ALLOWED_FAILURES * 2 {
+			assert_eq!(lockout.record_failure(None), None);
+		}
+		assert!(lockout.locked_out_for(None).is_none());
+		assert_eq!(lockout.failures(), u64::from(ALLOWED_FAILURES * 2));
+		assert_eq!(lockout.lockouts(), 0);
+	}
+
+	#[test]
+	fn test_client_address() {
+		let proxy = IpAddr::from([10, 0, 0, 1]);
+		let inner_proxy = IpAddr::from([10, 0, 0, 2]);
+		let client = IpAddr::from([192, 0, 2, 1]);
+		let trusted = [proxy, inner_proxy];
+
+		// untrusted peers are used as-is, even if they forward an address
+		assert_eq!(
+			client_address(Some(client), ["198.51.100.1"].into_iter(), &trusted),
+			Some(client)
+		);
+		assert_eq!(
+			client_address(None, ["192.0.2.1"].into_iter(), &trusted),
+			None
+		);
+
+		// the last untrusted address is used, since earlier ones could be spoofed
+		assert_eq!(
+			client_address(
+				Some(proxy),
+				["198.51.100.1, 192.0.2.1", "10.0.0.2"].into_iter(),
+				&trusted
+			),
+			Some(client)
+		);
+		assert_eq!(
+			client_address(Some(proxy), ["[2001:db8::1]:1234"].into_iter(), &trusted),
+			Some(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]))
+		);
+
+		// trusted proxies without a forwarded client are never locked out
+		assert_eq!(client_address(Some(proxy), [].into_iter(), &trusted), None);
+		assert_eq!(
+			client_address(Some(proxy), ["10.0.0.2"].into_iter(), &trusted),
+			None
+		);
+		assert_eq!(
+			client_address(Some(proxy), ["unknown"].into_iter(), &trusted),
+			None
+		);
+	}
+}
@@ -5,6 +5,7 @@
 use crate::{
 	constants::{APPLICATION_NAME, CONTROL_PORT, DOCUMENTATION_URL, QUALITYLESS_PRODUCT_NAME},
 	rpc::ResponseError,
+	tunnels::protocol::{FsErrorKind, ProcessErrorKind},
 };
 use std::fmt::Display;
 use thiserror::Error;
@@ -513,7 +514,55 @@ pub enum CodeError {
 	#[error("Could not check for update: {0}")]
 	UpdateCheckFailed(String),
 	#[error("Could not write connection token file: {0}")]
-	CouldNotCreateConnectionTokenFile(std::io::Error)
+	CouldNotCreateConnectionTokenFile(std::io::Error),
+	#[error("could not bind to the given host/port: {0}")]
+	CouldNotBindAddress(std::io::Error),
+	#[error("invalid TLS certificate or key: {0}")]
+	InvalidTlsCertificate(String),
+	#[error("'{0}' is not a valid commit hash")]
+	InvalidCommitHash(String),
+	#[error("invalid server archive: {0}")]
+	InvalidServerArchive(String),
+	#[error("server {0} is not available, only the server archive can be served offline")]
+	ServerNotAvailableOffline(String),
+	#[error("server {0} is in use, stop it first")]
+	ServerVersionInUse(String),
+	#[error("invalid password hash, expected one created by `serve-web hash-password` ({0})")]
+	InvalidPasswordHash(&'static str),
+	#[error("could not read the password hash file: {0}")]
+	CouldNotReadPasswordHash(std::io::Error),
+	#[error("invalid accounts file: {0}")]
+	InvalidAccountsFile(String),
+	#[error("--users-root requires users to be identified with --accounts-file or --trusted-user-header")]
+	MissingUserIdentity,
+	#[error("could not open the access log: {0}")]
+	CouldNotOpenAccessLog(std::io::Error),
+	#[error("invalid listen address `{0}`, expected tcp://<ip>:<port> or unix://<path>")]
+	InvalidListenAddress(String),
+	#[error("too many failed authentication attempts, try again in {0}s")]
+	AuthLockedOut(u64),
+	#[error("download appears corrupted, please retry (SHA-256 is {actual}, expected {expected})")]
+	DownloadChecksumMismatch { expected: String, actual: String },
+	#[error("no SHA-256 hash is published for {0}, so the download can't be verified. Set VSCODE_CLI_ALLOW_UNVERIFIED_DOWNLOADS to use it anyway")]
+	DownloadNotVerified(String),
+	#[error("{message}: {path}")]
+	FsError {
+		kind: FsErrorKind,
+		path: String,
+		message: String,
+	},
+	#[error("process {0} not found")]
+	ProcessNotFound(u32),
+	#[error("could not send {signal} to process {pid}: {reason}")]
+	ProcessSignalFailed {
+		pid: u32,
+		signal: String,
+		reason: String,
+	},
+	#[error("pseudo-terminals are not supported on this platform")]
+	PtyUnsupported,
+	#[error("could not generate a self-signed certificate ({0}), install OpenSSL or pass --tls-cert and --tls-key instead")]
+	SelfSignedCertFailed(String),
 }
 
 makeAnyError!(
@@ -547,6 +596,20 @@ makeAnyError!(
 	DbusConnectFailedError
 );
 
+impl AnyError {
+	/// Gets the code the error is sent with in RPC responses.
+	pub fn rpc_code(&self) -> i32 {
+		match self {
+			AnyError::CodeError(CodeError::FsError { kind, .. }) => *kind as i32,
+			AnyError::CodeError(CodeError::ProcessNotFound(_)) => ProcessErrorKind::NotFound as i32,
+			AnyError::CodeError(CodeError::ProcessSignalFailed { .. }) => {
+				ProcessErrorKind::SignalFailed as i32
+			}
+			_ => -1,
+		}
+	}
+}
+
 impl From<reqwest::Error> for AnyError {
 	fn from(e: reqwest::Error) -> AnyError {
 		AnyError::WrappedError(WrappedError::from(e))
@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use crate::util::errors::wrap;
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password, Select};
 use indicatif::ProgressBar;
 use std::fmt::Display;
 
@@ -67,3 +67,11 @@ pub fn prompt_placeholder(question: &str, placeholder: &str) -> Result<String, W
 		.interact_text()
 		.map_err(|e| wrap(e, "Failed to read confirm input"))
 }
+
+pub fn prompt_password_with_confirmation(question: &str) -> Result<String, WrappedError> {
+	Password::with_theme(&ColorfulTheme::default())
+		.with_prompt(question)
+		.with_confirmation("Confirm password", "Passwords do not match")
+		.interact()
+		.map_err(|e| wrap(e, "Failed to read password input"))
+}
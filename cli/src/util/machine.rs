@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{path::Path, time::Duration};
+use std::{io, path::Path, time::Duration};
 use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
 
 pub fn process_at_path_exists(pid: u32, name: &Path) -> bool {
@@ -59,3 +59,47 @@ pub async fn wait_until_exe_deleted(current_exe: &Path, poll_ms: u64) {
 		tokio::time::sleep(duration).await;
 	}
 }
+
+/// Gets the total and available bytes of the filesystem containing the path.
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)] // statvfs field types differ between platforms
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+	use std::os::unix::ffi::OsStrExt;
+
+	let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == -1 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let block_size = u64::from(stat.f_frsize);
+	Ok((
+		u64::from(stat.f_blocks) * block_size,
+		u64::from(stat.f_bavail) * block_size,
+	))
+}
+
+/// Gets the total and available bytes of the filesystem containing the path.
+#[cfg(windows)]
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+	use std::os::windows::ffi::OsStrExt;
+	use winapi::shared::ntdef::ULARGE_INTEGER;
+	use winapi::um::fileapi::GetDiskFreeSpaceExW;
+
+	let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+	let mut available: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+	let mut total: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+	if unsafe {
+		GetDiskFreeSpaceExW(
+			path.as_ptr(),
+			&mut available,
+			&mut total,
+			std::ptr::null_mut(),
+		)
+	} == 0
+	{
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(unsafe { (*total.QuadPart(), *available.QuadPart()) })
+}
@@ -10,6 +10,7 @@ use crate::update_service::Platform;
 use lazy_static::lazy_static;
 use regex::bytes::Regex as BinRegex;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tokio::fs;
 
 use super::errors::CodeError;
@@ -17,6 +18,7 @@ use super::errors::CodeError;
 lazy_static! {
 	static ref LDCONFIG_STDC_RE: Regex = Regex::new(r"libstdc\+\+.* => (.+)").unwrap();
 	static ref LDD_VERSION_RE: BinRegex = BinRegex::new(r"^ldd.*(.+)\.(.+)\s").unwrap();
+	static ref MUSL_VERSION_RE: BinRegex = BinRegex::new(r"Version ([0-9][0-9.]*)").unwrap();
 	static ref GENERIC_VERSION_RE: Regex = Regex::new(r"^([0-9]+)\.([0-9]+)$").unwrap();
 	static ref LIBSTD_CXX_VERSION_RE: BinRegex =
 		BinRegex::new(r"GLIBCXX_([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap();
@@ -25,6 +27,19 @@ lazy_static! {
 }
 
 const NIXOS_TEST_PATH: &str = "/etc/NIXOS";
+const MUSL_PATH: &str = if cfg!(target_arch = "aarch64") {
+	"/lib/ld-musl-aarch64.so.1"
+} else {
+	"/lib/ld-musl-x86_64.so.1"
+};
+
+/// C library found on the machine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LibcInfo {
+	/// Either "glibc" or "musl".
+	pub flavor: String,
+	pub version: Option<String>,
+}
 
 pub struct PreReqChecker {}
 
@@ -99,16 +114,41 @@ impl PreReqChecker {
 			name: QUALITYLESS_SERVER_NAME,
 		})
 	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub async fn libc(&self) -> Option<LibcInfo> {
+		None
+	}
+
+	/// Gets the C library of the machine, preferring glibc like `verify` does.
+	/// Returns None on other platforms, or if neither glibc nor musl is found.
+	#[cfg(target_os = "linux")]
+	pub async fn libc(&self) -> Option<LibcInfo> {
+		if let Some(v) = get_glibc_version().await {
+			return Some(LibcInfo {
+				flavor: "glibc".to_string(),
+				version: Some(format!("{}.{}", v.major, v.minor)),
+			});
+		}
+
+		if check_musl_interpreter().await.is_ok() {
+			// the musl loader prints its version when run without arguments
+			let version = capture_command(MUSL_PATH, std::iter::empty::<&str>())
+				.await
+				.ok()
+				.and_then(|o| extract_musl_version(&o.stderr));
+			return Some(LibcInfo {
+				flavor: "musl".to_string(),
+				version,
+			});
+		}
+
+		None
+	}
 }
 
 #[allow(dead_code)]
 async fn check_musl_interpreter() -> Result<(), String> {
-	const MUSL_PATH: &str = if cfg!(target_arch = "aarch64") {
-		"/lib/ld-musl-aarch64.so.1"
-	} else {
-		"/lib/ld-musl-x86_64.so.1"
-	};
-
 	if fs::metadata(MUSL_PATH).await.is_err() {
 		return Err(format!(
 			"find {}, which is required to run the {} in musl environments",
@@ -121,6 +161,22 @@ async fn check_musl_interpreter() -> Result<(), String> {
 
 #[allow(dead_code)]
 async fn check_glibc_version() -> Result<(), String> {
+	if let Some(v) = get_glibc_version().await {
+		return if v >= *MIN_LDD_VERSION {
+			Ok(())
+		} else {
+			Err(format!(
+				"find GLIBC >= 2.17 (but found {} instead) for GNU environments",
+				v
+			))
+		};
+	}
+
+	Ok(())
+}
+
+#[allow(dead_code)]
+async fn get_glibc_version() -> Option<SimpleSemver> {
 	#[cfg(target_env = "gnu")]
 	let version = {
 		let v = unsafe { libc::gnu_get_libc_version() };
@@ -136,18 +192,7 @@ async fn check_glibc_version() -> Result<(), String> {
 			.and_then(|o| extract_ldd_version(&o.stdout))
 	};
 
-	if let Some(v) = version {
-		return if v >= *MIN_LDD_VERSION {
-			Ok(())
-		} else {
-			Err(format!(
-				"find GLIBC >= 2.17 (but found {} instead) for GNU environments",
-				v
-			))
-		};
-	}
-
-	Ok(())
+	version
 }
 
 /// Check for nixos to avoid mandating glibc versions. See:
@@ -222,6 +267,14 @@ fn extract_ldd_version(output: &[u8]) -> Option<SimpleSemver> {
 	})
 }
 
+#[allow(dead_code)]
+fn extract_musl_version(output: &[u8]) -> Option<String> {
+	MUSL_VERSION_RE
+		.captures(output)
+		.and_then(|m| m.get(1))
+		.map(|m| String::from_utf8_lossy(m.as_bytes()).to_string())
+}
+
 #[allow(dead_code)]
 fn extract_generic_version(output: &str) -> Option<SimpleSemver> {
 	GENERIC_VERSION_RE.captures(output).map(|m| SimpleSemver {
@@ -346,4 +399,17 @@ mod tests {
 			Some(SimpleSemver::new(2, 31, 0)),
 		);
 	}
+
+	#[test]
+	fn test_extract_musl_version() {
+		let actual = "musl libc (x86_64)
+Version 1.2.4
+Dynamic Program Loader
+Usage: /lib/ld-musl-x86_64.so.1 [options] [--] pathname [args]"
+			.to_owned()
+			.into_bytes();
+
+		assert_eq!(extract_musl_version(&actual), Some("1.2.4".to_owned()));
+		assert_eq!(extract_musl_version(b"nothing here!"), None);
+	}
 }
@@ -0,0 +1,115 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Support for systemd socket activation, where the service manager binds
+//! the listening sockets and passes them to the process as file descriptors.
+//! See `sd_listen_fds(3)`.
+
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::log;
+
+/// Number of sockets passed to this process, set by `take_env`.
+#[cfg(unix)]
+static LISTEN_FDS: AtomicI32 = AtomicI32::new(0);
+
+/// A listening socket passed by the service manager.
+pub enum ActivatedListener {
+	Tcp(TcpListener),
+	#[cfg(unix)]
+	Unix(std::os::unix::net::UnixListener),
+}
+
+/// Reads the socket activation environment variables and removes them, so
+/// they aren't inherited by child processes. Since this changes the
+/// environment, it must be called at startup before any threads are spawned.
+#[cfg(unix)]
+pub fn take_env() {
+	let pid = std::env::var("LISTEN_PID").ok();
+	let fds = std::env::var("LISTEN_FDS").ok();
+	for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+		std::env::remove_var(var);
+	}
+
+	// the variables are meant for another process if the pid doesn't match
+	if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+		return;
+	}
+	if let Some(n) = fds.and_then(|n| n.parse::<i32>().ok()) {
+		LISTEN_FDS.store(n, Ordering::SeqCst);
+	}
+}
+
+/// Reads the socket activation environment variables, which is not
+/// supported on this platform.
+#[cfg(not(unix))]
+pub fn take_env() {}
+
+/// Takes the listening sockets passed with socket activation, as found by
+/// `take_env`. Returns an empty list if the process wasn't socket activated
+/// or the sockets were already taken.
+#[cfg(unix)]
+pub fn take_listeners(log: &log::Logger) -> Vec<ActivatedListener> {
+	use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+	use std::os::unix::net::UnixListener;
+
+	/// First file descriptor passed by the service manager.
+	const LISTEN_FDS_START: RawFd = 3;
+
+	let count: RawFd = match LISTEN_FDS.swap(0, Ordering::SeqCst) {
+		n if n > 0 => n,
+		_ => return vec![],
+	};
+
+	let mut listeners = Vec::with_capacity(count as usize);
+	for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+		unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+		// getsockname() tells what kind of socket the descriptor is, and
+		// the standard library errors if it's not the family it expects.
+		let unix = unsafe { UnixListener::from_raw_fd(fd) };
+		if unix.local_addr().is_ok() {
+			listeners.push(ActivatedListener::Unix(unix));
+			continue;
+		}
+
+		let tcp = unsafe { TcpListener::from_raw_fd(unix.into_raw_fd()) };
+		if tcp.local_addr().is_ok() {
+			listeners.push(ActivatedListener::Tcp(tcp));
+		} else {
+			warning!(log, "Ignoring unsupported socket {} passed by systemd", fd);
+		}
+	}
+
+	listeners
+}
+
+/// Takes the listening sockets passed with socket activation, which is not
+/// supported on this platform.
+#[cfg(not(unix))]
+pub fn take_listeners(_log: &log::Logger) -> Vec<ActivatedListener> {
+	vec![]
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_take_env_other_pid() {
+		std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+		std::env::set_var("LISTEN_FDS", "1");
+		std::env::set_var("LISTEN_FDNAMES", "http");
+
+		take_env();
+
+		assert_eq!(LISTEN_FDS.load(Ordering::SeqCst), 0);
+		for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+			assert!(std::env::var_os(var).is_none(), "{} was not removed", var);
+		}
+	}
+}
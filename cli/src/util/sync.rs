@@ -33,6 +33,11 @@ where
 	pub fn is_open(&self) -> bool {
 		self.0.borrow().is_some()
 	}
+
+	/// Gets the barrier's value if it's been opened, without waiting.
+	pub fn try_get(&self) -> Option<T> {
+		self.0.borrow().clone()
+	}
 }
 
 #[async_trait]
@@ -57,6 +62,11 @@ impl<T: Clone> BarrierOpener<T> {
 			}
 		});
 	}
+
+	/// Gets whether the barrier has been opened.
+	pub fn is_open(&self) -> bool {
+		self.0.borrow().is_some()
+	}
 }
 
 /// The Barrier is something that can be opened once from one side,